@@ -19,7 +19,7 @@ fn hashmap_test_1() {
     //get
     let yellow = color_hex_map.get(&String::from("yellow"));
     if yellow.is_none() == false {
-        log!("found yellow {}\n", format!("0x{:08X}", yellow.unwrap()));
+        log!("found yellow 0x{:08X}\n", yellow.unwrap());
     }
 
     //remove
@@ -34,7 +34,7 @@ fn hashmap_test_1() {
 
     //dump key, val
     for (key, val) in &color_hex_map {
-        log!("{} {}\n", key, format!("0x{:08X}", val));
+        log!("{} 0x{:08X}\n", key, val);
     }
 }
 
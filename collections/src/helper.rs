@@ -12,19 +12,8 @@ macro_rules! function {
 
 #[macro_export]
 macro_rules! log {
-    ($($args:expr),*) => {{
-        let mut c: i32 = 0;
-        let mut fmt: String = String::from("");
-        $(
-            if c == 0 {
-                fmt = $args.to_string();
-                c+=1;
-            } else {
-                fmt = fmt.replacen("{}", &$args.to_string(), 1);
-            }
-        )*
-        print!("[{}] {}", crate::function!(), fmt);
-        c
+    ($($args:tt)+) => {{
+        print!("[{}] {}", crate::function!(), format_args!($($args)+));
     }}
 }
 
@@ -0,0 +1,211 @@
+//async logging: the macros hand records to a bounded channel and a writer thread
+//passes them on to the sinks, so a slow sink no longer stalls the logging thread.
+//  async_log::start(AsyncConfig { capacity: 4096, overflow: OverflowPolicy::DropNewest });
+//  ...
+//  async_log::shutdown();
+//Once started a panic hook writes out what is queued before the panic message.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use crate::logger::{self, Level};
+use crate::sink::{Location, Record};
+
+const WRITER_NAME: &str = "libhelper-log";
+
+
+//what a caller does when the channel is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    //wait for the writer thread, nothing is lost
+    Block,
+    //drop the record, the writer logs how many were dropped
+    DropNewest,
+}
+
+/**
+ *  AsyncConfig
+ **/
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsyncConfig {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for AsyncConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 8192,
+            overflow: OverflowPolicy::Block,
+        }
+    }
+}
+
+enum Command {
+    Record(Record),
+    Flush(mpsc::Sender<()>),
+    Shutdown,
+}
+
+struct AsyncBackend {
+    sender: mpsc::SyncSender<Command>,
+    overflow: OverflowPolicy,
+    running: AtomicBool,
+    //dropped since the writer last reported it, and in total
+    dropped: AtomicU64,
+    dropped_total: AtomicU64,
+    writer: Mutex<Option<JoinHandle<mpsc::Receiver<Command>>>>,
+}
+
+static BACKEND: OnceLock<AsyncBackend> = OnceLock::new();
+
+
+//false if it was started before, once per process
+pub fn start(config: AsyncConfig) -> bool {
+    let (sender, receiver) = mpsc::sync_channel(config.capacity.max(1));
+    let backend = AsyncBackend {
+        sender,
+        overflow: config.overflow,
+        running: AtomicBool::new(true),
+        dropped: AtomicU64::new(0),
+        dropped_total: AtomicU64::new(0),
+        writer: Mutex::new(None),
+    };
+    if BACKEND.set(backend).is_err() {
+        return false;
+    }
+    let writer = thread::Builder::new()
+        .name(WRITER_NAME.to_string())
+        .spawn(move || run_writer(receiver))
+        .expect("failed to spawn the log writer thread");
+    *BACKEND.get().unwrap().writer.lock().unwrap() = Some(writer);
+
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        flush();
+        previous_hook(info);
+    }));
+    true
+}
+
+pub fn is_running() -> bool {
+    BACKEND.get().is_some_and(|backend| backend.running.load(Ordering::Acquire))
+}
+
+pub fn dropped_count() -> u64 {
+    BACKEND.get().map_or(0, |backend| backend.dropped_total.load(Ordering::Relaxed))
+}
+
+fn on_writer_thread() -> bool {
+    thread::current().name() == Some(WRITER_NAME)
+}
+
+fn running_backend() -> Option<&'static AsyncBackend> {
+    BACKEND.get().filter(|backend| backend.running.load(Ordering::Acquire) && !on_writer_thread())
+}
+
+//hands record to the writer thread, gives it back if that is not running
+pub fn submit(record: Record) -> Option<Record> {
+    let backend = match running_backend() {
+        Some(backend) => backend,
+        None => return Some(record),
+    };
+    let command = Command::Record(record);
+    let returned = match backend.overflow {
+        OverflowPolicy::Block => backend.sender.send(command).err().map(|mpsc::SendError(command)| command),
+        OverflowPolicy::DropNewest => match backend.sender.try_send(command) {
+            Ok(()) => None,
+            Err(mpsc::TrySendError::Full(_)) => {
+                backend.dropped.fetch_add(1, Ordering::Relaxed);
+                backend.dropped_total.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Err(mpsc::TrySendError::Disconnected(command)) => Some(command),
+        },
+    };
+    match returned {
+        //the writer is gone, shutdown() raced with us
+        Some(Command::Record(record)) => Some(record),
+        _ => None,
+    }
+}
+
+//waits until everything queued so far was written and the sinks were flushed.
+//false if the writer thread is not running
+pub fn flush() -> bool {
+    let backend = match running_backend() {
+        Some(backend) => backend,
+        None => return false,
+    };
+    let (ack_sender, ack_receiver) = mpsc::channel();
+    if backend.sender.send(Command::Flush(ack_sender)).is_err() {
+        return false;
+    }
+    ack_receiver.recv().is_ok()
+}
+
+//writes out everything queued and stops the writer thread, later records are
+//written by the logging thread again
+pub fn shutdown() {
+    let backend = match BACKEND.get() {
+        Some(backend) => backend,
+        None => return,
+    };
+    if !backend.running.swap(false, Ordering::AcqRel) {
+        return;
+    }
+    let _ = backend.sender.send(Command::Shutdown);
+    let writer = backend.writer.lock().unwrap().take();
+    if let Some(Ok(receiver)) = writer.map(JoinHandle::join) {
+        //sent while the writer was stopping
+        while let Ok(command) = receiver.try_recv() {
+            handle(backend, command);
+        }
+    }
+    report_dropped(backend);
+    logger::flush_sinks();
+}
+
+fn report_dropped(backend: &AsyncBackend) {
+    let dropped = backend.dropped.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        let location = Location {
+            module_path: module_path!(),
+            function: crate::function!(),
+            file: file!(),
+            line: line!(),
+        };
+        logger::dispatch(&Record::new(Level::Warn, location, format!("dropped {} log records, the queue was full\n", dropped)));
+    }
+}
+
+fn handle(backend: &AsyncBackend, command: Command) {
+    match command {
+        Command::Record(record) => {
+            report_dropped(backend);
+            logger::dispatch(&record);
+        }
+        Command::Flush(ack_sender) => {
+            report_dropped(backend);
+            logger::flush_sinks();
+            let _ = ack_sender.send(());
+        }
+        Command::Shutdown => {}
+    }
+}
+
+fn run_writer(receiver: mpsc::Receiver<Command>) -> mpsc::Receiver<Command> {
+    let backend = BACKEND.get().unwrap();
+    while let Ok(command) = receiver.recv() {
+        if let Command::Shutdown = command {
+            break;
+        }
+        //a panicking sink must not take the receiver and what is queued in it down too
+        if panic::catch_unwind(AssertUnwindSafe(|| handle(backend, command))).is_err() {
+            eprintln!("[{}] a log sink panicked, the record is lost", crate::function!());
+        }
+    }
+    receiver
+}
+
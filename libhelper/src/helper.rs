@@ -10,22 +10,61 @@ macro_rules! function {
     }}
 }
 
+//format_args!() syntax, e.g. log!("{:?} 0x{:08X}\n", value, id = 7), optionally
+//led by key = value fields that end up in the record, e.g. info!(user_id = 7, "login\n")
+//log! logs at info level without a level label, filtered like info!
 #[macro_export]
 macro_rules! log {
-    ($($args:expr),*) => {{
-        let mut c: i32 = 0;
-        let mut fmt: String = String::from("");
-        $(
-            if c == 0 {
-                fmt = $args.to_string();
-                c+=1;
-            } else {
-                fmt = fmt.replacen("{}", &$args.to_string(), 1);
-            }
-        )*
-        print!("[{}] {}", crate::function!(), fmt);
-        c
-    }}
+    ($($args:tt)+) => { $crate::__log_at!([$crate::logger::Level::Info, false] [] $($args)+) }
+}
+
+//collects the leading fields one by one, then writes the record
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_at {
+    ([$level:expr, $show_level:expr] [$($fields:tt)*] $key:ident = $value:expr, $($rest:tt)+) => {
+        $crate::__log_at!([$level, $show_level] [$($fields)* (stringify!($key), $value)] $($rest)+)
+    };
+    ([$level:expr, $show_level:expr] [$($fields:tt)*] $key:ident = $value:expr) => {
+        $crate::__log_at!([$level, $show_level] [$($fields)* (stringify!($key), $value)] "")
+    };
+    ([$level:expr, $show_level:expr] [$(($key:expr, $value:expr))*] $($args:tt)+) => {{
+        if $level <= $crate::logger::STATIC_MAX_LEVEL && $crate::logger::enabled($level, module_path!()) {
+            let location = $crate::sink::Location {
+                module_path: module_path!(),
+                function: $crate::function!(),
+                file: file!(),
+                line: line!(),
+            };
+            let fields = vec![$(($key, $crate::logger::field_value(&$value))),*];
+            $crate::logger::write($level, $show_level, location, fields, format_args!($($args)+));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($args:tt)+) => { $crate::__log_at!([$crate::logger::Level::Error, true] [] $($args)+) }
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($args:tt)+) => { $crate::__log_at!([$crate::logger::Level::Warn, true] [] $($args)+) }
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($args:tt)+) => { $crate::__log_at!([$crate::logger::Level::Info, true] [] $($args)+) }
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($args:tt)+) => { $crate::__log_at!([$crate::logger::Level::Debug, true] [] $($args)+) }
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($args:tt)+) => { $crate::__log_at!([$crate::logger::Level::Trace, true] [] $($args)+) }
 }
 
 #[macro_export]
@@ -45,12 +84,12 @@ macro_rules! ErrStack {
         if errmsg.find("> ").is_none() {
             errmsg = format!("\n> {}", errmsg);
         }
-        std::io::Error::new($e.kind(), format!("{} \n> ({}:{}) {}", errmsg,  crate::function!(), line!(), fmt))
+        std::io::Error::new($e.kind(), format!("{} \n> ({}:{}) {}", errmsg,  $crate::function!(), line!(), fmt))
     }};
 }
 
-pub fn type_of<T>(_: &T) -> String {
-    return  String::from(std::any::type_name::<T>());
+pub fn type_of<T: ?Sized>(_: &T) -> String {
+    String::from(std::any::type_name::<T>())
 }
 
 
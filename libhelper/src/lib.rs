@@ -1,7 +1,10 @@
 pub mod helper;
+pub mod logger;
+pub mod sink;
+pub mod async_log;
 
 #[no_mangle]
-pub extern fn rust_function_a() {
+pub extern "C" fn rust_function_a() {
     println!("Hello this is rust function a\n");
 }
 
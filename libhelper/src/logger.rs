@@ -0,0 +1,324 @@
+//levels and the per module filter behind log!/error!/warn!/info!/debug!/trace!.
+//The filter is a comma separated config string like
+//  msgq=debug,others::json=warn,info
+//where a bare level is the default for every other module. It is read from
+//LIBHELPER_LOG on first use unless set_filter()/parse_filter() came first.
+//Records that pass go to the sinks added with add_sink(), each with a filter
+//of its own, or to stdout if there are none. With async_log::start() they are
+//written by a writer thread instead of the logging one.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicI32, Ordering};
+use serde::Serialize;
+use crate::sink::{Location, LogSink, Record};
+
+pub const ENV_VAR: &str = "LIBHELPER_LOG";
+
+
+/**
+ *  Level
+ **/
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    //only used in filters, switches a module off
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("unknown log level '{}'", s)),
+        }
+    }
+}
+
+//levels above this are compiled out, picked with the max_level_* features
+pub const STATIC_MAX_LEVEL: Level = if cfg!(feature = "max_level_off") {
+    Level::Off
+} else if cfg!(feature = "max_level_error") {
+    Level::Error
+} else if cfg!(feature = "max_level_warn") {
+    Level::Warn
+} else if cfg!(feature = "max_level_info") {
+    Level::Info
+} else if cfg!(feature = "max_level_debug") {
+    Level::Debug
+} else {
+    Level::Trace
+};
+
+
+/**
+ *  LogFilter
+ **/
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogFilter {
+    default_level: Level,
+    //(module path, level), longest path first so the most specific one wins
+    directives: Vec<(String, Level)>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self::new(Level::Info)
+    }
+}
+
+impl LogFilter {
+    pub fn new(default_level: Level) -> Self {
+        Self {
+            default_level,
+            directives: Vec::new(),
+        }
+    }
+
+    //module_path covers itself and its submodules, e.g. "others" covers "others::json"
+    pub fn directive(mut self, module_path: &str, level: Level) -> Self {
+        self.directives.retain(|(path, _)| path != module_path);
+        self.directives.push((module_path.to_string(), level));
+        self.directives.sort_by_key(|(path, _)| std::cmp::Reverse(path.len()));
+        self
+    }
+
+    pub fn parse(config: &str) -> Result<Self, String> {
+        let mut filter = Self::default();
+        for part in config.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            filter = match part.split_once('=') {
+                Some((module_path, level)) => filter.directive(module_path.trim(), level.parse()?),
+                None => {
+                    filter.default_level = part.parse()?;
+                    filter
+                }
+            };
+        }
+        Ok(filter)
+    }
+
+    pub fn level_for(&self, module_path: &str) -> Level {
+        for (path, level) in self.directives.iter() {
+            if module_path == path || (module_path.starts_with(path.as_str()) && module_path[path.len()..].starts_with("::")) {
+                return *level;
+            }
+        }
+        self.default_level
+    }
+
+    pub fn enabled(&self, level: Level, module_path: &str) -> bool {
+        level != Level::Off && level <= self.level_for(module_path)
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+
+static FILTER: RwLock<Option<LogFilter>> = RwLock::new(None);
+
+pub fn set_filter(filter: LogFilter) {
+    *FILTER.write().unwrap() = Some(filter);
+}
+
+pub fn parse_filter(config: &str) -> Result<(), String> {
+    set_filter(LogFilter::parse(config)?);
+    Ok(())
+}
+
+//LIBHELPER_LOG, or info for everything if it is not set or can't be parsed
+fn filter_from_env() -> LogFilter {
+    match std::env::var(ENV_VAR) {
+        Ok(config) => LogFilter::parse(&config).unwrap_or_else(|e| {
+            eprintln!("[{}] ignoring {}: {}", crate::function!(), ENV_VAR, e);
+            LogFilter::default()
+        }),
+        Err(_) => LogFilter::default(),
+    }
+}
+
+pub fn enabled(level: Level, module_path: &str) -> bool {
+    if level > STATIC_MAX_LEVEL {
+        return false;
+    }
+    if let Some(filter) = FILTER.read().unwrap().as_ref() {
+        return filter.enabled(level, module_path);
+    }
+    FILTER.write().unwrap().get_or_insert_with(filter_from_env).enabled(level, module_path)
+}
+
+struct SinkEntry {
+    id: i32,
+    filter: LogFilter,
+    sink: Arc<dyn LogSink>,
+}
+
+static SINKS: RwLock<Vec<SinkEntry>> = RwLock::new(Vec::new());
+static NEXT_SINK_ID: AtomicI32 = AtomicI32::new(1);
+
+//e.g. at program start
+//  add_sink(LogFilter::new(Level::Warn), Arc::new(StderrSink::new()));
+//  add_sink(LogFilter::parse("info")?, Arc::new(StdoutSink::new().format(LogFormat::JsonLines)));
+//  add_sink(LogFilter::parse("debug")?, Arc::new(FileSink::new("app.log")?));
+//returns the id for remove_sink()
+pub fn add_sink(filter: LogFilter, sink: Arc<dyn LogSink>) -> i32 {
+    let id = NEXT_SINK_ID.fetch_add(1, Ordering::SeqCst);
+    SINKS.write().unwrap().push(SinkEntry { id, filter, sink });
+    id
+}
+
+//false if there is no sink with this id
+pub fn remove_sink(id: i32) -> bool {
+    let mut sinks = SINKS.write().unwrap();
+    let len = sinks.len();
+    sinks.retain(|entry| entry.id != id);
+    sinks.len() != len
+}
+
+//back to plain stdout
+pub fn clear_sinks() {
+    SINKS.write().unwrap().clear();
+}
+
+//waits for the async writer thread too, if it runs
+pub fn flush() {
+    if !crate::async_log::flush() {
+        flush_sinks();
+    }
+}
+
+pub(crate) fn flush_sinks() {
+    for entry in SINKS.read().unwrap().iter() {
+        entry.sink.flush();
+    }
+}
+
+pub fn dispatch(record: &Record) {
+    let sinks = SINKS.read().unwrap();
+    if sinks.is_empty() {
+        print!("{}", record.format());
+        return;
+    }
+    for entry in sinks.iter() {
+        if entry.filter.enabled(record.level, &record.module_path) {
+            entry.sink.write(record);
+        }
+    }
+}
+
+//value of a key = value field, e.g. a string, a number or a serde struct
+pub fn field_value<T: Serialize + ?Sized>(value: &T) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or_else(|e| serde_json::Value::String(e.to_string()))
+}
+
+//called by the macros once enabled() said yes. log! shows no level and keeps
+//its plain "[function] message" output
+pub fn write(level: Level, show_level: bool, location: Location, fields: Vec<(&str, serde_json::Value)>, message: fmt::Arguments) {
+    let mut record = Record::new(level, location, message.to_string());
+    record.show_level = show_level;
+    record.fields = fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect();
+    if let Some(record) = crate::async_log::submit(record) {
+        dispatch(&record);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn most_specific_module_wins() {
+        let filter = LogFilter::parse("msgq=debug, others::json=warn,others=trace,error").unwrap();
+        assert_eq!(filter.level_for("msgq"), Level::Debug);
+        assert_eq!(filter.level_for("msgq::generic"), Level::Debug);
+        assert_eq!(filter.level_for("msgqx"), Level::Error);
+        assert_eq!(filter.level_for("others::json"), Level::Warn);
+        assert_eq!(filter.level_for("others::file"), Level::Trace);
+        assert!(filter.enabled(Level::Warn, "others::json::inner"));
+        assert!(!filter.enabled(Level::Info, "others::json::inner"));
+        assert!(!filter.enabled(Level::Off, "others"));
+    }
+
+    #[test]
+    fn bad_config_is_rejected() {
+        assert_eq!(LogFilter::parse(""), Ok(LogFilter::new(Level::Info)));
+        assert!(LogFilter::parse("msgq=loud").is_err());
+        assert_eq!("Warn".parse::<Level>(), Ok(Level::Warn));
+    }
+
+    #[test]
+    fn macros_take_format_args() {
+        let ring_buffer = Arc::new(crate::sink::RingBufferSink::new(16));
+        set_filter(LogFilter::default().directive(module_path!(), Level::Trace));
+        let sink_id = add_sink(LogFilter::new(Level::Off).directive(module_path!(), Level::Trace), ring_buffer.clone());
+        let text = "{} stays as is";
+        crate::log!("no placeholders\n");
+        crate::log!("{:?} 0x{:08X} {name}\n", text, 255, name = "named",);
+        crate::debug!("{}\n", text);
+        crate::info!(user_id = 7, name = text, "login {}\n", 1);
+        crate::info!(user_id = 7);
+        flush();
+        remove_sink(sink_id);
+
+        let lines: Vec<String> = ring_buffer.lines().into_iter().filter(|line| line.contains("macros_take_format_args")).collect();
+        assert_eq!(lines, vec![
+            "[libhelper::logger::tests::macros_take_format_args] no placeholders\n",
+            "[libhelper::logger::tests::macros_take_format_args] \"{} stays as is\" 0x000000FF named\n",
+            "DEBUG [libhelper::logger::tests::macros_take_format_args] {} stays as is\n",
+            "INFO  [libhelper::logger::tests::macros_take_format_args] login 1 user_id=7 name=\"{} stays as is\"\n",
+            "INFO  [libhelper::logger::tests::macros_take_format_args] user_id=7",
+        ]);
+    }
+
+    #[test]
+    fn sinks_get_what_their_filter_lets_through() {
+        let ring_buffer = Arc::new(crate::sink::RingBufferSink::new(8));
+        let sink_id = add_sink(LogFilter::new(Level::Off).directive(module_path!(), Level::Warn), ring_buffer.clone());
+        crate::error!("kept\n");
+        crate::info!("dropped\n");
+        crate::log!("dropped too\n");
+        flush();
+        assert!(remove_sink(sink_id));
+        assert!(!remove_sink(sink_id));
+        crate::error!("not kept, the sink is gone\n");
+        let lines: Vec<String> = ring_buffer.lines().into_iter().filter(|line| line.contains("sinks_get_what")).collect();
+        assert_eq!(lines, vec!["ERROR [libhelper::logger::tests::sinks_get_what_their_filter_lets_through] kept\n"]);
+    }
+}
@@ -0,0 +1,472 @@
+//where log records end up. Every sink added with logger::add_sink() gets the
+//records its own LogFilter lets through, stdout gets everything while none is added.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use crate::logger::Level;
+
+
+//where a record was logged, filled in by the macros
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub module_path: &'static str,
+    pub function: &'static str,
+    pub file: &'static str,
+    pub line: u32,
+}
+
+/**
+ *  Record
+ **/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub timestamp: SystemTime,
+    pub level: Level,
+    //false for log!, which prints without the level
+    pub show_level: bool,
+    pub thread: Option<String>,
+    pub module_path: String,
+    pub function: String,
+    pub file: String,
+    pub line: u32,
+    //key = value fields of the macro call, in call order
+    pub fields: Vec<(String, serde_json::Value)>,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'static str,
+    thread: Option<&'a str>,
+    module_path: &'a str,
+    function: &'a str,
+    file: &'a str,
+    line: u32,
+    message: &'a str,
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+//2026-10-18T21:33:56.123Z
+fn rfc3339(timestamp: SystemTime) -> String {
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    //days to civil date, http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day,
+        secs % 86400 / 3600, secs % 3600 / 60, secs % 60, since_epoch.subsec_millis())
+}
+
+impl Record {
+    //now, on the calling thread
+    pub fn new(level: Level, location: Location, message: String) -> Self {
+        Self {
+            timestamp: SystemTime::now(),
+            level,
+            show_level: true,
+            thread: thread::current().name().map(String::from),
+            module_path: location.module_path.to_string(),
+            function: location.function.to_string(),
+            file: location.file.to_string(),
+            line: location.line,
+            fields: Vec::new(),
+            message,
+        }
+    }
+
+    //"[function] message key=value" or "LEVEL [function] message key=value", the
+    //message keeps its own newline
+    pub fn format(&self) -> String {
+        let mut line = if self.show_level {
+            format!("{:<5} [{}] ", self.level, self.function)
+        } else {
+            format!("[{}] ", self.function)
+        };
+        let message = self.message.strip_suffix('\n');
+        line.push_str(message.unwrap_or(&self.message));
+        for (key, value) in self.fields.iter() {
+            if !line.ends_with(' ') {
+                line.push(' ');
+            }
+            line.push_str(&format!("{}={}", key, value));
+        }
+        if message.is_some() {
+            line.push('\n');
+        }
+        line
+    }
+
+    //one JSON object and a newline
+    pub fn to_json_line(&self) -> String {
+        let json_record = JsonRecord {
+            timestamp: rfc3339(self.timestamp),
+            level: self.level.as_str(),
+            thread: self.thread.as_deref(),
+            module_path: &self.module_path,
+            function: &self.function,
+            file: &self.file,
+            line: self.line,
+            message: self.message.trim_end_matches('\n'),
+            fields: self.fields.iter().cloned().collect(),
+        };
+        let mut line = serde_json::to_string(&json_record).unwrap_or_else(|e| format!("{{\"error\":{:?}}}", e.to_string()));
+        line.push('\n');
+        line
+    }
+}
+
+//how a sink turns a record into text
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    //Record::format()
+    #[default]
+    Text,
+    //Record::to_json_line()
+    JsonLines,
+}
+
+impl LogFormat {
+    pub fn format(&self, record: &Record) -> String {
+        match self {
+            LogFormat::Text => record.format(),
+            LogFormat::JsonLines => record.to_json_line(),
+        }
+    }
+}
+
+
+pub trait LogSink: Send + Sync {
+    fn write(&self, record: &Record);
+    fn flush(&self) {}
+}
+
+
+/**
+ *  StdoutSink
+ **/
+#[derive(Default)]
+pub struct StdoutSink {
+    format: LogFormat,
+}
+
+impl StdoutSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+}
+
+impl LogSink for StdoutSink {
+    fn write(&self, record: &Record) {
+        print!("{}", self.format.format(record));
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+/**
+ *  StderrSink
+ **/
+#[derive(Default)]
+pub struct StderrSink {
+    format: LogFormat,
+}
+
+impl StderrSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+}
+
+impl LogSink for StderrSink {
+    fn write(&self, record: &Record) {
+        eprint!("{}", self.format.format(record));
+    }
+}
+
+
+/**
+ *  FileRotation
+ *  log.txt is moved to log.txt.1 (log.txt.1 to log.txt.2 ...) once it reaches
+ *  max_bytes or is older than max_age, at most keep old files are kept
+ **/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileRotation {
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub keep: usize,
+}
+
+struct OpenFile {
+    file: File,
+    size: u64,
+    opened_at: Instant,
+}
+
+/**
+ *  FileSink
+ **/
+pub struct FileSink {
+    path: PathBuf,
+    rotation: FileRotation,
+    format: LogFormat,
+    open_file: Mutex<OpenFile>,
+}
+
+impl FileSink {
+    //appends to path, never rotates
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::with_rotation(path, FileRotation::default())
+    }
+
+    pub fn with_rotation<P: AsRef<Path>>(path: P, rotation: FileRotation) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let open_file = Self::open(&path)?;
+        Ok(Self {
+            path,
+            rotation,
+            format: LogFormat::Text,
+            open_file: Mutex::new(open_file),
+        })
+    }
+
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn open(path: &Path) -> io::Result<OpenFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(OpenFile {
+            file,
+            size,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn needs_rotation(&self, open_file: &OpenFile, len: u64) -> bool {
+        let too_big = self.rotation.max_bytes.is_some_and(|max_bytes| open_file.size > 0 && open_file.size + len > max_bytes);
+        let too_old = self.rotation.max_age.is_some_and(|max_age| open_file.opened_at.elapsed() >= max_age);
+        too_big || too_old
+    }
+
+    fn rotate(&self, open_file: &mut OpenFile) -> io::Result<()> {
+        open_file.file.flush()?;
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.rotation.keep));
+            for index in (1..self.rotation.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        *open_file = Self::open(&self.path)?;
+        Ok(())
+    }
+
+    fn write_line(&self, line: &str) -> io::Result<()> {
+        let mut open_file = self.open_file.lock().unwrap();
+        if self.needs_rotation(&open_file, line.len() as u64) {
+            if let Err(e) = self.rotate(&mut open_file) {
+                //keep logging to path, or to the old file if even that fails, and try
+                //rotating again once it is due next time
+                eprintln!("[{}] rotating {} failed: {}", crate::function!(), self.path.display(), e);
+                match Self::open(&self.path) {
+                    Ok(reopened) => *open_file = reopened,
+                    Err(_) => {
+                        open_file.size = 0;
+                        open_file.opened_at = Instant::now();
+                    }
+                }
+            }
+        }
+        open_file.file.write_all(line.as_bytes())?;
+        open_file.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl LogSink for FileSink {
+    fn write(&self, record: &Record) {
+        if let Err(e) = self.write_line(&self.format.format(record)) {
+            eprintln!("[{}] {}: {}", crate::function!(), self.path.display(), e);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.open_file.lock().unwrap().file.flush();
+    }
+}
+
+
+/**
+ *  RingBufferSink
+ *  the last capacity formatted records, e.g. for a crash report or a test
+ **/
+pub struct RingBufferSink {
+    capacity: usize,
+    format: LogFormat,
+    lines: Mutex<VecDeque<String>>,
+}
+
+impl RingBufferSink {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            format: LogFormat::Text,
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    //oldest first
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn clear(&self) {
+        self.lines.lock().unwrap().clear();
+    }
+}
+
+impl LogSink for RingBufferSink {
+    fn write(&self, record: &Record) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(self.format.format(record));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(message: &str) -> Record {
+        let location = Location {
+            module_path: "libhelper::sink::tests",
+            function: "libhelper::sink::tests::record",
+            file: "src/sink.rs",
+            line: 1,
+        };
+        Record::new(Level::Warn, location, message.to_string())
+    }
+
+    #[test]
+    fn ring_buffer_keeps_the_last_records() {
+        let ring_buffer = RingBufferSink::new(2);
+        for message in ["a\n", "b\n", "c\n"] {
+            ring_buffer.write(&record(message));
+        }
+        assert_eq!(ring_buffer.lines(), vec!["WARN  [libhelper::sink::tests::record] b\n", "WARN  [libhelper::sink::tests::record] c\n"]);
+    }
+
+    #[test]
+    fn fields_go_to_text_and_json_lines() {
+        let mut record = record("login\n");
+        record.timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        record.fields = vec![(String::from("user_id"), serde_json::json!(7)), (String::from("name"), serde_json::json!("ann"))];
+        assert_eq!(record.format(), "WARN  [libhelper::sink::tests::record] login user_id=7 name=\"ann\"\n");
+
+        let json_line = record.to_json_line();
+        assert!(json_line.ends_with("}\n"));
+        let value: serde_json::Value = serde_json::from_str(&json_line).unwrap();
+        assert_eq!(value["timestamp"], "2023-11-14T22:13:20.123Z");
+        assert_eq!(value["level"], "WARN");
+        assert_eq!(value["message"], "login");
+        assert_eq!(value["line"], 1);
+        assert_eq!(value["fields"]["user_id"], 7);
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn file_rotates_by_size_and_keeps_old_files() {
+        let dir = std::env::temp_dir().join(format!("libhelper-sink-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log.txt");
+        let line_len = record("0123456789\n").format().len() as u64;
+        let file_sink = FileSink::with_rotation(&path, FileRotation { max_bytes: Some(line_len * 2), max_age: None, keep: 2 }).unwrap();
+        for _ in 0..7 {
+            file_sink.write(&record("0123456789\n"));
+        }
+        file_sink.flush();
+
+        let size_of = |path: PathBuf| fs::metadata(path).map(|metadata| metadata.len()).ok();
+        assert_eq!(size_of(path.clone()), Some(line_len));
+        assert_eq!(size_of(file_sink.rotated_path(1)), Some(line_len * 2));
+        assert_eq!(size_of(file_sink.rotated_path(2)), Some(line_len * 2));
+        assert_eq!(size_of(file_sink.rotated_path(3)), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_rotation_keeps_writing() {
+        let dir = std::env::temp_dir().join(format!("libhelper-sink-rotation-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log.txt");
+        let line_len = record("0123456789\n").format().len() as u64;
+        let file_sink = FileSink::with_rotation(&path, FileRotation { max_bytes: Some(line_len), max_age: None, keep: 1 }).unwrap();
+        file_sink.write(&record("0123456789\n"));
+        //the rename in rotate() fails with NotFound
+        fs::remove_file(&path).unwrap();
+        for _ in 0..3 {
+            file_sink.write(&record("0123456789\n"));
+        }
+        file_sink.flush();
+
+        assert_eq!(fs::metadata(&path).unwrap().len(), line_len);
+        assert_eq!(fs::metadata(file_sink.rotated_path(1)).unwrap().len(), line_len);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,51 @@
+//in a binary of its own: start() is once per process and the sinks added here
+//would otherwise see the records of every other test
+
+use std::sync::Arc;
+use std::thread;
+use libhelper::async_log::{self, AsyncConfig, OverflowPolicy};
+use libhelper::logger::{self, Level, LogFilter};
+use libhelper::sink::{LogSink, Record, RingBufferSink};
+
+struct PanicSink;
+
+impl LogSink for PanicSink {
+    fn write(&self, record: &Record) {
+        if record.message.contains("boom") {
+            panic!("sink failed");
+        }
+    }
+}
+
+#[test]
+fn queued_records_are_written_on_shutdown() {
+    let ring_buffer = Arc::new(RingBufferSink::new(64));
+    let ring_buffer_id = logger::add_sink(LogFilter::new(Level::Off).directive(module_path!(), Level::Info), ring_buffer.clone());
+    assert!(async_log::start(AsyncConfig { capacity: 4, overflow: OverflowPolicy::Block }));
+    assert!(!async_log::start(AsyncConfig::default()));
+    let panic_id = logger::add_sink(LogFilter::new(Level::Off).directive(module_path!(), Level::Info), Arc::new(PanicSink));
+    libhelper::info!("boom\n");
+
+    let loggers: Vec<_> = (0..2).map(|id| thread::spawn(move || {
+        for index in 0..10 {
+            libhelper::info!("{} {}\n", id, index);
+        }
+    })).collect();
+    for logger in loggers {
+        logger.join().unwrap();
+    }
+    async_log::shutdown();
+    assert!(!async_log::is_running());
+    assert!(logger::remove_sink(panic_id));
+    assert!(logger::remove_sink(ring_buffer_id));
+
+    let lines = ring_buffer.lines();
+    //boom got to the ring buffer before the panic
+    assert_eq!(lines.len(), 21);
+    let messages: Vec<&str> = lines.iter().map(|line| line.rsplit("] ").next().unwrap()).collect();
+    for id in 0..2 {
+        let expected: Vec<String> = (0..10).map(|index| format!("{} {}\n", id, index)).collect();
+        assert_eq!(messages.iter().filter(|message| message.starts_with(&format!("{} ", id))).collect::<Vec<_>>(), expected.iter().collect::<Vec<_>>());
+    }
+    assert_eq!(async_log::dropped_count(), 0);
+}
@@ -0,0 +1,28 @@
+#!/bin/bash
+
+list_exports() {
+	nm -D target/debug/libmsgq.so  | grep ' T msgq_'
+}
+
+header() {
+	cbindgen --config cbindgen.toml --output include/msgq.h
+}
+
+build_rust_lib() {
+	cargo build
+}
+
+build() {
+	build_rust_lib
+	gcc -g -o main main.c -Iinclude -lmsgq -L./target/debug -lpthread
+}
+
+run() {
+	LD_LIBRARY_PATH=./target/debug/ ./main
+}
+
+clean() {
+	rm -rf main target
+}
+
+$@
@@ -0,0 +1,98 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::any::Any;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use msgq::message_queue::*;
+
+
+//counts every heap allocation made by the process
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const COUNT: usize = 1_000_000;
+
+
+struct HelloMessage {
+    value: u64,
+}
+
+impl Message for HelloMessage {
+    fn handler_id(&self) -> i32 {
+        1
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct HelloHandler {
+    sum: AtomicUsize,
+}
+
+impl MessageHandler for HelloHandler {
+    fn on_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+        match option_box_msg {
+            Some(box_msg) => self.on_message_ref(box_msg.as_ref()).unwrap_or(true),
+            None => false,
+        }
+    }
+
+    fn on_message_ref(&self, msg: &(dyn Message + Send)) -> Option<bool> {
+        let hello_msg = msg.as_any().downcast_ref::<HelloMessage>()?;
+        self.sum.fetch_add(hello_msg.value as usize, Ordering::Relaxed);
+        Some(true)
+    }
+}
+
+
+//posts COUNT messages in bursts of `burst`, then lets the queue drain them
+fn run(name: &str, pool_size: Option<usize>, burst: usize) {
+    let message_queue = MessageQueue::new();
+    message_queue.register_message_handler(1, Arc::new(HelloHandler { sum: AtomicUsize::new(0) }));
+    let message_pool = pool_size.map(|max_size| message_queue.enable_message_pool::<HelloMessage>(max_size));
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for round in 0..COUNT / burst {
+        for i in 0..burst {
+            let value = (round * burst + i) as u64;
+            message_queue.post_message(Some(message_queue.obtain_message(HelloMessage { value })));
+        }
+        for _ in 0..burst {
+            message_queue.process_next_message();
+        }
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!("{:<24} {:>8.1} ns/msg {:>8.3} allocs/msg", name,
+        elapsed.as_nanos() as f64 / COUNT as f64, allocations as f64 / COUNT as f64);
+    if let Some(message_pool) = message_pool {
+        let stats = message_pool.stats();
+        println!("{:<24} hit rate {:.1}% {:?}", "", stats.hits as f64 * 100.0 / (stats.hits + stats.misses) as f64, stats);
+    }
+}
+
+fn main() {
+    run("no pool, burst 1", None, 1);
+    run("pool 64, burst 1", Some(64), 1);
+    run("pool 64, burst 50", Some(64), 50);
+    run("pool 64, burst 100", Some(64), 100);
+}
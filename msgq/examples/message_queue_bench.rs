@@ -0,0 +1,117 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::any::Any;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use msgq::generic;
+use msgq::message_queue::*;
+
+
+//counts every heap allocation made by the process
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const COUNT: usize = 1_000_000;
+
+
+/**
+ *  dynamic flavor: Box<dyn Message + Send> + downcast
+ **/
+struct HelloMessage {
+    value: u64,
+}
+
+impl Message for HelloMessage {
+    fn handler_id(&self) -> i32 {
+        1
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct DynHandler {
+    sum: AtomicUsize,
+}
+
+impl MessageHandler for DynHandler {
+    fn on_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+        if let Some(hello_msg) = option_box_msg.as_ref().and_then(|box_msg| box_msg.as_any().downcast_ref::<HelloMessage>()) {
+            self.sum.fetch_add(hello_msg.value as usize, Ordering::Relaxed);
+        }
+        true
+    }
+}
+
+
+/**
+ *  generic flavor: plain enum by value
+ **/
+enum BenchMessage {
+    Hello(u64),
+}
+
+struct TypedHandler {
+    sum: AtomicUsize,
+}
+
+impl generic::MessageHandler<BenchMessage> for TypedHandler {
+    fn on_message(&self, message_option: Option<BenchMessage>) -> bool {
+        if let Some(BenchMessage::Hello(value)) = message_option {
+            self.sum.fetch_add(value as usize, Ordering::Relaxed);
+        }
+        true
+    }
+}
+
+
+fn report(name: &str, start: Instant, allocations: usize) {
+    let elapsed = start.elapsed();
+    println!("{:<28} {:>10.1} ns/msg {:>8.3} allocs/msg",
+        name, elapsed.as_nanos() as f64 / COUNT as f64, allocations as f64 / COUNT as f64);
+}
+
+fn main() {
+    let message_queue = MessageQueue::new();
+    message_queue.register_message_handler(1, Arc::new(DynHandler { sum: AtomicUsize::new(0) }));
+    //warm up so the queue's own buffer is already grown
+    message_queue.post_message(Some(Box::new(HelloMessage { value: 0 })));
+    message_queue.process_next_message();
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for i in 0..COUNT {
+        message_queue.post_message(Some(Box::new(HelloMessage { value: i as u64 })));
+        message_queue.process_next_message();
+    }
+    report("MessageQueue (Box<dyn>)", start, ALLOCATIONS.load(Ordering::Relaxed) - allocations);
+
+    let typed_queue = generic::MessageQueue::<BenchMessage>::new();
+    typed_queue.set_message_handler(Arc::new(TypedHandler { sum: AtomicUsize::new(0) }));
+    typed_queue.post_message(Some(BenchMessage::Hello(0)));
+    typed_queue.process_next_message();
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for i in 0..COUNT {
+        typed_queue.post_message(Some(BenchMessage::Hello(i as u64)));
+        typed_queue.process_next_message();
+    }
+    report("generic::MessageQueue<enum>", start, ALLOCATIONS.load(Ordering::Relaxed) - allocations);
+}
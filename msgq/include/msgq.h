@@ -0,0 +1,81 @@
+/*
+ *  msgq C API
+ *
+ *  Handles come from msgq_queue_create(), msgq_thread_start() and
+ *  msgq_queue_receive*(), and go back exactly once to msgq_queue_destroy(),
+ *  msgq_thread_stop() and msgq_message_free(). Functions returning int32_t
+ *  return 0, or -1 on NULL or otherwise bad arguments.
+ *
+ *  Handlers registered with msgq_queue_register_handler() run on the thread
+ *  of msgq_thread_start() with their user_data, and get the payload only for
+ *  the duration of the call. Returning false stops that thread.
+ *
+ *  msgq_queue_receive() blocks until a message or msgq_queue_post_stop(), and
+ *  returns NULL for the latter; msgq_queue_receive_timeout() also returns
+ *  NULL after timeout_ms.
+ */
+
+#ifndef MSGQ_H
+#define MSGQ_H
+
+/* Generated by cbindgen from src/ffi.rs, run ./build.sh header after changing it. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+
+
+
+
+
+
+
+
+typedef struct MsgqMessage MsgqMessage;
+
+typedef struct MsgqQueue MsgqQueue;
+
+typedef struct MsgqThread MsgqThread;
+
+typedef bool (*MsgqHandlerFn)(int32_t handler_id, const uint8_t *data, size_t len, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+struct MsgqQueue *msgq_queue_create(const char *name);
+
+void msgq_queue_destroy(struct MsgqQueue *queue);
+
+int32_t msgq_queue_post(const struct MsgqQueue *queue,
+                        int32_t handler_id,
+                        const uint8_t *data,
+                        size_t len);
+
+int32_t msgq_queue_post_stop(const struct MsgqQueue *queue);
+
+struct MsgqMessage *msgq_queue_receive(const struct MsgqQueue *queue);
+
+struct MsgqMessage *msgq_queue_receive_timeout(const struct MsgqQueue *queue, uint32_t timeout_ms);
+
+int32_t msgq_queue_register_handler(const struct MsgqQueue *queue,
+                                    int32_t handler_id,
+                                    MsgqHandlerFn callback,
+                                    void *user_data);
+
+int32_t msgq_message_handler_id(const struct MsgqMessage *message);
+
+const uint8_t *msgq_message_data(const struct MsgqMessage *message, size_t *len);
+
+void msgq_message_free(struct MsgqMessage *message);
+
+struct MsgqThread *msgq_thread_start(const struct MsgqQueue *queue);
+
+void msgq_thread_stop(struct MsgqThread *thread);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* MSGQ_H */
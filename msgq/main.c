@@ -0,0 +1,70 @@
+#include<stdio.h>
+#include<string.h>
+#include<assert.h>
+#include<pthread.h>
+#include"msgq.h"
+
+
+struct counter {
+	pthread_mutex_t mutex;
+	int messages;
+	size_t bytes;
+};
+
+static bool on_message(int32_t handler_id, const uint8_t *data, size_t len, void *user_data)
+{
+	struct counter *counter = user_data;
+
+	pthread_mutex_lock(&counter->mutex);
+	counter->messages++;
+	counter->bytes += len;
+	pthread_mutex_unlock(&counter->mutex);
+	printf("handler %d got '%.*s'\n", handler_id, (int)len, (const char *)data);
+	return true;
+}
+
+static void test_message_thread()
+{
+	struct counter counter = { PTHREAD_MUTEX_INITIALIZER, 0, 0 };
+	MsgqQueue *queue = msgq_queue_create("c-thread");
+	MsgqThread *thread;
+
+	assert(msgq_queue_register_handler(queue, 1, on_message, &counter) == 0);
+	thread = msgq_thread_start(queue);
+	msgq_queue_post(queue, 1, (const uint8_t *)"hello", 5);
+	msgq_queue_post(queue, 1, (const uint8_t *)"world", 5);
+	msgq_thread_stop(thread);
+	msgq_queue_destroy(queue);
+
+	assert(counter.messages == 2);
+	assert(counter.bytes == 10);
+}
+
+static void test_receive()
+{
+	MsgqQueue *queue = msgq_queue_create(NULL);
+	MsgqMessage *message;
+	const uint8_t *data;
+	size_t len;
+
+	assert(msgq_queue_receive_timeout(queue, 10) == NULL);
+	msgq_queue_post(queue, 7, (const uint8_t *)"payload", 7);
+	message = msgq_queue_receive(queue);
+	assert(message != NULL);
+	assert(msgq_message_handler_id(message) == 7);
+	data = msgq_message_data(message, &len);
+	assert(len == 7 && memcmp(data, "payload", len) == 0);
+	msgq_message_free(message);
+
+	msgq_queue_post_stop(queue);
+	assert(msgq_queue_receive(queue) == NULL);
+	msgq_queue_destroy(queue);
+}
+
+int main(int argc, const char *argv[])
+{
+	test_message_thread();
+	test_receive();
+	printf("msgq C API ok\n");
+	return 0;
+}
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+use serde::de::Error;
+use crate::message_queue::*;
+use crate::clock::{Clock, SystemClock};
+
+
+/**
+ *  MessageQueueConfig
+ *
+ *  e.g. the "worker" section of
+ *      {
+ *          "worker": {
+ *              "name": "worker",
+ *              "capacity": 1024,
+ *              "thread_name": "worker-thread",
+ *              "stack_size": 262144,
+ *              "cpu_affinity": [2, 3],
+ *              "fairness": "WeightedDeficitRoundRobin",
+ *              "handler_weights": { "1": 4 }
+ *          }
+ *      }
+ *
+ *  capacity counts every pending entry, messages and sync barriers. Cancelled
+ *  messages leave the queue on cancel() and don't count. It has to be at least 1.
+ **/
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageQueueConfig {
+    pub name: Option<String>,
+    pub capacity: Option<usize>,
+    pub thread_name: Option<String>,
+    pub stack_size: Option<usize>,
+    pub cpu_affinity: Vec<usize>,
+    pub fairness: Option<FairnessMode>,
+    pub handler_weights: HashMap<i32, u32>,
+}
+
+impl MessageQueueConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.capacity == Some(0) {
+            return Err(String::from("capacity must be at least 1"));
+        }
+        if let Some(cpu) = self.cpu_affinity.iter().find(|cpu| **cpu >= MAX_CPUS) {
+            return Err(format!("cpu {} in cpu_affinity is out of range, at most {} cpus", cpu, MAX_CPUS));
+        }
+        Ok(())
+    }
+}
+
+
+/**
+ *  MessageQueueBuilder
+ **/
+#[derive(Default)]
+pub struct MessageQueueBuilder {
+    config: MessageQueueConfig,
+    default_handler: Option<Arc<dyn MessageHandler + Send + Sync>>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl MessageQueueBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_config(config: MessageQueueConfig) -> Self {
+        Self {
+            config,
+            default_handler: None,
+            clock: None,
+        }
+    }
+
+    //section is a key of the top level JSON object, or "" for the whole document
+    pub fn from_json(json: &str, section: &str) -> serde_json::Result<Self> {
+        let mut value: serde_json::Value = serde_json::from_str(json)?;
+        if !section.is_empty() {
+            value = match value.get_mut(section) {
+                Some(section_value) => section_value.take(),
+                None => return Err(serde_json::Error::custom(format!("missing section '{}'", section))),
+            };
+        }
+        let config: MessageQueueConfig = serde_json::from_value(value)?;
+        config.validate().map_err(serde_json::Error::custom)?;
+        Ok(Self::from_config(config))
+    }
+
+    pub fn config(&self) -> &MessageQueueConfig {
+        &self.config
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.config.name = Some(name.to_string());
+        self
+    }
+
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.config.capacity = Some(capacity);
+        self
+    }
+
+    pub fn thread_name(mut self, thread_name: &str) -> Self {
+        self.config.thread_name = Some(thread_name.to_string());
+        self
+    }
+
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        self.config.stack_size = Some(stack_size);
+        self
+    }
+
+    pub fn cpu_affinity(mut self, cpus: Vec<usize>) -> Self {
+        self.config.cpu_affinity = cpus;
+        self
+    }
+
+    pub fn fairness(mut self, mode: FairnessMode) -> Self {
+        self.config.fairness = Some(mode);
+        self
+    }
+
+    pub fn handler_weight(mut self, handler_id: i32, weight: u32) -> Self {
+        self.config.handler_weights.insert(handler_id, weight);
+        self
+    }
+
+    pub fn default_handler(mut self, handler: Arc<dyn MessageHandler + Send + Sync>) -> Self {
+        self.default_handler = Some(handler);
+        self
+    }
+
+    //SystemClock if not set
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    //InvalidInput if the config does not validate()
+    pub fn build_queue(&self) -> io::Result<Arc<MessageQueue>> {
+        self.config.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let clock = self.clock.clone().unwrap_or_else(|| Arc::new(SystemClock));
+        let message_queue = Arc::new(MessageQueue::with_clock(self.config.name.as_deref(), self.config.capacity, clock));
+        if let Some(handler) = self.default_handler.as_ref() {
+            message_queue.set_default_message_handler(handler.clone());
+        }
+        for (handler_id, weight) in self.config.handler_weights.iter() {
+            message_queue.set_handler_weight(*handler_id, *weight);
+        }
+        if let Some(mode) = self.config.fairness {
+            message_queue.set_fairness(mode);
+        }
+        Ok(message_queue)
+    }
+
+    //the thread is not started yet
+    pub fn build(&self) -> io::Result<(Arc<MessageQueue>, MessageThread)> {
+        let message_queue = self.build_queue()?;
+        let thread_config = ThreadConfig {
+            name: self.config.thread_name.clone(),
+            stack_size: self.config.stack_size,
+            cpu_affinity: self.config.cpu_affinity.clone(),
+        };
+        let message_thread = MessageThread::with_config(message_queue.clone(), thread_config);
+        Ok((message_queue, message_thread))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::any::Any;
+    use std::sync::Mutex;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    struct NamedMessage {
+    }
+
+    impl Message for NamedMessage {
+        fn handler_id(&self) -> i32 {
+            42
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct ThreadNameHandler {
+        tx: Mutex<mpsc::Sender<Option<String>>>,
+    }
+
+    impl MessageHandler for ThreadNameHandler {
+        fn on_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+            if option_box_msg.is_none() {
+                return false;
+            }
+            self.tx.lock().unwrap().send(thread::current().name().map(String::from)).unwrap();
+            true
+        }
+    }
+
+    #[test]
+    fn builds_from_json_section() {
+        let json = r#"
+            {
+                "other": {},
+                "worker": {
+                    "name": "worker",
+                    "capacity": 2,
+                    "stack_size": 262144,
+                    "fairness": "RoundRobin"
+                }
+            }"#;
+
+        let (tx, rx) = mpsc::channel();
+        let builder = MessageQueueBuilder::from_json(json, "worker").unwrap()
+            .default_handler(Arc::new(ThreadNameHandler { tx: Mutex::new(tx) }));
+        assert_eq!(builder.config().capacity, Some(2));
+        assert!(MessageQueueBuilder::from_json(json, "missing").is_err());
+        assert!(MessageQueueBuilder::from_json(r#"{"nmae": "typo"}"#, "").is_err());
+        assert!(MessageQueueBuilder::from_json(r#"{"cpu_affinity": [0, 100000]}"#, "").is_err());
+        assert!(MessageQueueBuilder::new().cpu_affinity(vec![MAX_CPUS]).build().is_err());
+
+        let (message_queue, mut message_thread) = builder.build().unwrap();
+        assert_eq!(message_queue.name(), Some("worker"));
+        assert_eq!(message_thread.thread_name(), Some("worker"));
+        assert_eq!(message_queue.fairness(), FairnessMode::RoundRobin);
+
+        //no handler 42 registered: goes to the default handler on the named thread
+        message_thread.start();
+        for _ in 0..5 {
+            message_queue.post_message(Some(Box::new(NamedMessage {})));
+        }
+        for _ in 0..5 {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().as_deref(), Some("worker"));
+        }
+        message_thread.stop();
+    }
+
+    #[test]
+    fn capacity_blocks_posting() {
+        assert!(MessageQueueBuilder::new().capacity(0).build_queue().is_err());
+        assert!(MessageQueueBuilder::from_json(r#"{"capacity": 0}"#, "").is_err());
+        let message_queue = MessageQueueBuilder::new().capacity(1).build_queue().unwrap();
+        message_queue.post_message(Some(Box::new(NamedMessage {})));
+
+        let poster_queue = message_queue.clone();
+        let poster = thread::spawn(move || {
+            poster_queue.post_message(Some(Box::new(NamedMessage {})));
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!poster.is_finished());
+
+        assert!(message_queue.get_message().is_some());
+        poster.join().unwrap();
+        assert!(message_queue.get_message().is_some());
+    }
+}
@@ -0,0 +1,165 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::cell::RefCell;
+use std::fmt;
+
+
+/**
+ *  CancelListener
+ *  a queue holding messages posted with the token, told when it is cancelled
+ **/
+pub(crate) trait CancelListener {
+    fn on_cancelled(&self);
+}
+
+struct TokenState {
+    cancelled: AtomicBool,
+    parent: Option<Arc<TokenState>>,
+    children: Mutex<Vec<Weak<TokenState>>>,
+    listeners: Mutex<Vec<Weak<dyn CancelListener + Send + Sync>>>,
+}
+
+impl TokenState {
+    fn new(parent: Option<Arc<TokenState>>) -> Self {
+        Self {
+            cancelled: AtomicBool::new(false),
+            parent,
+            children: Mutex::new(Vec::new()),
+            listeners: Mutex::new(Vec::new()),
+        }
+    }
+
+    //listeners of this token and of every child token under it
+    fn collect_listeners(&self, listeners: &mut Vec<Arc<dyn CancelListener + Send + Sync>>) {
+        listeners.extend(self.listeners.lock().unwrap().iter().filter_map(|listener| listener.upgrade()));
+        let children: Vec<_> = self.children.lock().unwrap().iter().filter_map(|child| child.upgrade()).collect();
+        for child in children {
+            child.collect_listeners(listeners);
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        if self.cancelled.load(Ordering::Acquire) {
+            return true;
+        }
+        match self.parent.as_ref() {
+            Some(parent) => parent.is_cancelled(),
+            None => false,
+        }
+    }
+}
+
+
+/**
+ *  CancellationToken
+ *
+ *  Returned by MessageQueue::post_message_cancellable(). cancel() takes the messages
+ *  still pending out of the queues they were posted to right away, so they no longer
+ *  count toward a queue's capacity, and a handler that is already running one can
+ *  poll CancellationToken::current().
+ *  Posting many messages with one token, or with child_token()s of it, cancels
+ *  all of them at once.
+ **/
+#[derive(Clone)]
+pub struct CancellationToken {
+    state: Arc<TokenState>,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CancellationToken {{ cancelled: {} }}", self.is_cancelled())
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(TokenState::new(None)),
+        }
+    }
+
+    //cancelled together with self, but can also be cancelled on its own
+    pub fn child_token(&self) -> Self {
+        let state = Arc::new(TokenState::new(Some(self.state.clone())));
+        let mut children = self.state.children.lock().unwrap();
+        children.retain(|child| child.strong_count() > 0);
+        children.push(Arc::downgrade(&state));
+        Self {
+            state,
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Release);
+        let mut listeners = Vec::new();
+        self.state.collect_listeners(&mut listeners);
+        for listener in listeners {
+            listener.on_cancelled();
+        }
+    }
+
+    //called by a queue before it takes a message posted with this token, once per queue
+    pub(crate) fn add_listener(&self, listener: Weak<dyn CancelListener + Send + Sync>) {
+        let mut listeners = self.state.listeners.lock().unwrap();
+        listeners.retain(|known| known.strong_count() > 0);
+        if !listeners.iter().any(|known| Weak::ptr_eq(known, &listener)) {
+            listeners.push(listener);
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.is_cancelled()
+    }
+
+    /**
+     *  Token of the message the calling MessageThread is dispatching right now,
+     *  None if it was posted without one.
+     **/
+    pub fn current() -> Option<CancellationToken> {
+        CURRENT_CANCELLATION_TOKEN.with(|current| current.borrow().clone())
+    }
+}
+
+//true when the message being handled on this thread was cancelled after it was dequeued
+pub fn is_cancellation_requested() -> bool {
+    CURRENT_CANCELLATION_TOKEN.with(|current| current.borrow().as_ref().is_some_and(|token| token.is_cancelled()))
+}
+
+
+thread_local! {
+    static CURRENT_CANCELLATION_TOKEN: RefCell<Option<CancellationToken>> = const { RefCell::new(None) };
+}
+
+//returns the previous token so nested dispatching can restore it
+pub(crate) fn set_current_cancellation_token(token_option: Option<CancellationToken>) -> Option<CancellationToken> {
+    CURRENT_CANCELLATION_TOKEN.with(|current| std::mem::replace(&mut *current.borrow_mut(), token_option))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn child_tokens_follow_their_parent() {
+        let group = CancellationToken::new();
+        let first = group.child_token();
+        let second = group.child_token();
+        let grandchild = second.child_token();
+
+        first.cancel();
+        assert!(first.is_cancelled());
+        assert!(!second.is_cancelled());
+        assert!(!group.is_cancelled());
+
+        group.cancel();
+        assert!(second.is_cancelled());
+        assert!(grandchild.is_cancelled());
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::{Mutex, Weak};
+use std::time::{Duration, Instant};
+use crate::sync;
+
+
+/**
+ *  ClockListener
+ *  told when a VirtualClock moves, so waiters can look at their deadlines again
+ **/
+pub trait ClockListener {
+    fn on_clock_advanced(&self);
+}
+
+/**
+ *  Clock
+ *
+ *  Time source of a MessageQueue: deadlines, TTLs, rate limits and
+ *  get_message_timeout() all go by now(). SystemClock is the default.
+ **/
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    //how long to block in real time before looking at now() again,
+    //None: until a post or the clock itself wakes the waiter
+    fn real_timeout(&self, until: Instant) -> Option<Duration>;
+
+    fn add_listener(&self, _listener: Weak<dyn ClockListener + Send + Sync>) {
+    }
+}
+
+
+/**
+ *  SystemClock
+ **/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn real_timeout(&self, until: Instant) -> Option<Duration> {
+        Some(until.saturating_duration_since(Instant::now()))
+    }
+}
+
+
+/**
+ *  VirtualClock
+ *
+ *  Only moves when advance() is called, so tests of timeouts and expiry need no
+ *  real sleeps. Waiters on queues using it block until a post or an advance().
+ **/
+pub struct VirtualClock {
+    start: Instant,
+    elapsed: sync::Mutex<Duration>,
+    listeners: Mutex<Vec<Weak<dyn ClockListener + Send + Sync>>>,
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: sync::Mutex::new(Duration::ZERO),
+            listeners: Mutex::new(Vec::new()),
+        }
+    }
+
+    //virtual time since new()
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+
+        let listeners: Vec<_> = {
+            let mut listeners = self.listeners.lock().unwrap();
+            listeners.retain(|listener| listener.strong_count() > 0);
+            listeners.iter().filter_map(|listener| listener.upgrade()).collect()
+        };
+        for listener in listeners {
+            listener.on_clock_advanced();
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn real_timeout(&self, _until: Instant) -> Option<Duration> {
+        None
+    }
+
+    fn add_listener(&self, listener: Weak<dyn ClockListener + Send + Sync>) {
+        self.listeners.lock().unwrap().push(listener);
+    }
+}
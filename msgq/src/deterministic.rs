@@ -0,0 +1,310 @@
+use std::sync::Arc;
+use std::time::Duration;
+use crate::generic;
+use crate::message_queue::*;
+use crate::clock::VirtualClock;
+
+
+/**
+ *  DispatchRecord
+ **/
+#[derive(Clone, Debug, PartialEq)]
+pub struct DispatchRecord {
+    pub step: usize,
+    pub queue_index: usize,
+    pub queue_name: Option<String>,
+    pub handler_id: i32,
+    pub type_name: String,
+    //VirtualClock time of the dispatch
+    pub at: Duration,
+    //what the handler returned
+    pub handled: bool,
+}
+
+
+/**
+ *  DeterministicExecutor
+ *
+ *  Runs the handlers of its queues on the calling thread, one message per step(),
+ *  taking the queues round-robin. All queues share one VirtualClock, so deadlines,
+ *  TTLs and rate limits only move with advance(). Every dispatch is recorded for
+ *  assertions.
+ *
+ *  Like a MessageThread, a queue runs its idle handlers once it has nothing
+ *  deliverable, again only after its next dispatch, and is done with once its
+ *  stop message comes out.
+ **/
+pub struct DeterministicExecutor {
+    clock: Arc<VirtualClock>,
+    queues: Vec<ExecutorQueue>,
+    next_queue: usize,
+    records: Vec<DispatchRecord>,
+}
+
+struct ExecutorQueue {
+    message_queue: Arc<MessageQueue>,
+    //idle handlers ran since the last dispatch
+    idle: bool,
+    stopped: bool,
+}
+
+impl Default for DeterministicExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeterministicExecutor {
+    pub fn new() -> Self {
+        Self {
+            clock: Arc::new(VirtualClock::new()),
+            queues: Vec::new(),
+            next_queue: 0,
+            records: Vec::new(),
+        }
+    }
+
+    pub fn clock(&self) -> &Arc<VirtualClock> {
+        &self.clock
+    }
+
+    //a queue on the executor's clock, served by step()
+    pub fn new_queue(&mut self, name: &str) -> Arc<MessageQueue> {
+        let message_queue = Arc::new(MessageQueue::with_clock(Some(name), None, self.clock.clone()));
+        self.queues.push(ExecutorQueue {
+            message_queue: message_queue.clone(),
+            idle: false,
+            stopped: false,
+        });
+        message_queue
+    }
+
+    //true once the stop message of the queue at queue_index came out, step() leaves it alone then
+    pub fn is_stopped(&self, queue_index: usize) -> bool {
+        self.queues.get(queue_index).is_some_and(|queue| queue.stopped)
+    }
+
+    //the next message of queue_index, running its idle handlers first if there is none
+    fn next_message(&mut self, queue_index: usize) -> Option<(Box<dyn Message + Send>, MessageMeta)> {
+        loop {
+            let queue = &mut self.queues[queue_index];
+            if queue.stopped {
+                return None;
+            }
+            match queue.message_queue.inner().try_get_message_with_meta() {
+                Some((Some(box_msg), meta)) => return Some((box_msg, meta)),
+                Some((None, _)) => {
+                    queue.stopped = true;
+                    return None;
+                }
+                None if queue.idle => return None,
+                None => {
+                    queue.idle = true;
+                    let message_queue = queue.message_queue.clone();
+                    Self::run_as_current(&message_queue, || message_queue.inner().run_idle_handlers());
+                }
+            }
+        }
+    }
+
+    fn run_as_current<T>(message_queue: &Arc<MessageQueue>, f: impl FnOnce() -> T) -> T {
+        let previous = generic::current_message_queue();
+        MessageQueue::set_current(Some(message_queue.clone()));
+        let result = f();
+        generic::set_current_message_queue(previous);
+        result
+    }
+
+    /**
+     *  Dispatch one message, None if no queue has anything deliverable now.
+     **/
+    pub fn step(&mut self) -> Option<&DispatchRecord> {
+        for i in 0..self.queues.len() {
+            let queue_index = (self.next_queue + i) % self.queues.len();
+            let (box_msg, meta) = match self.next_message(queue_index) {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            let message_queue = self.queues[queue_index].message_queue.clone();
+            self.queues[queue_index].idle = false;
+            let handler_id = box_msg.handler_id();
+            let type_name = box_msg.type_name();
+            let handled = Self::run_as_current(&message_queue, || message_queue.inner().dispatch_message_with_meta(Some(box_msg), meta));
+
+            self.next_queue = queue_index + 1;
+            self.records.push(DispatchRecord {
+                step: self.records.len(),
+                queue_index,
+                queue_name: message_queue.name().map(String::from),
+                handler_id,
+                type_name,
+                at: self.clock.elapsed(),
+                handled,
+            });
+            return self.records.last();
+        }
+        None
+    }
+
+    //steps until every queue is empty, stopped or waiting for the clock, returns the steps taken
+    pub fn run_until_idle(&mut self) -> usize {
+        let mut steps = 0;
+        while self.step().is_some() {
+            steps += 1;
+        }
+        steps
+    }
+
+    pub fn advance(&mut self, duration: Duration) -> usize {
+        self.clock.advance(duration);
+        self.run_until_idle()
+    }
+
+    pub fn records(&self) -> &[DispatchRecord] {
+        &self.records
+    }
+
+    pub fn take_records(&mut self) -> Vec<DispatchRecord> {
+        std::mem::take(&mut self.records)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::any::Any;
+    use std::thread;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+    use crate::handler::Handler;
+
+    struct StepMessage {
+        handler_id: i32,
+        forward_to: Option<Handler>,
+    }
+
+    impl Message for StepMessage {
+        fn handler_id(&self) -> i32 {
+            self.handler_id
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct ForwardHandler {
+    }
+
+    impl MessageHandler for ForwardHandler {
+        fn on_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+            let box_msg = option_box_msg.unwrap();
+            let step_msg = box_msg.as_any().downcast_ref::<StepMessage>().unwrap();
+            //runs with the queue as current, like on a MessageThread
+            assert!(MessageQueue::current().is_some());
+            if let Some(handler) = step_msg.forward_to.as_ref() {
+                handler.post_message(Box::new(StepMessage { handler_id: handler.handler_id(), forward_to: None }));
+            }
+            true
+        }
+    }
+
+    #[test]
+    fn records_dispatch_order_and_follows_the_virtual_clock() {
+        let mut executor = DeterministicExecutor::new();
+        let queue_a = executor.new_queue("a");
+        let queue_b = executor.new_queue("b");
+        queue_a.register_message_handler(1, Arc::new(ForwardHandler {}));
+        queue_b.register_message_handler(2, Arc::new(ForwardHandler {}));
+        queue_b.set_handler_limit(2, HandlerLimit { rate_per_second: Some(1.0), burst: 1, max_concurrent: None });
+
+        let to_b = Handler::new(queue_b.clone(), 2);
+        queue_a.post_message(Some(Box::new(StepMessage { handler_id: 1, forward_to: Some(to_b.clone()) })));
+        queue_a.post_message(Some(Box::new(StepMessage { handler_id: 1, forward_to: Some(to_b) })));
+        queue_a.post_message_with_ttl(Box::new(StepMessage { handler_id: 1, forward_to: None }), Duration::from_secs(10));
+
+        //b gets one message per virtual second
+        assert_eq!(executor.run_until_idle(), 4);
+        let order: Vec<(Option<&str>, i32)> = executor.records().iter().map(|record| (record.queue_name.as_deref(), record.handler_id)).collect();
+        assert_eq!(order, vec![(Some("a"), 1), (Some("b"), 2), (Some("a"), 1), (Some("a"), 1)]);
+        assert_eq!(executor.advance(Duration::from_millis(999)), 0);
+        assert_eq!(executor.advance(Duration::from_millis(1)), 1);
+        assert_eq!(executor.records()[4].at, Duration::from_secs(1));
+        assert!(executor.records()[4].type_name.ends_with("StepMessage"));
+
+        //expiry goes by the virtual clock too
+        queue_a.post_message_with_ttl(Box::new(StepMessage { handler_id: 1, forward_to: None }), Duration::from_secs(10));
+        executor.clock().advance(Duration::from_secs(11));
+        assert_eq!(executor.run_until_idle(), 0);
+        assert_eq!(queue_a.expired_count(), 1);
+    }
+
+    #[test]
+    fn advance_wakes_blocked_timeouts() {
+        let executor = DeterministicExecutor::new();
+        let clock = executor.clock().clone();
+        let message_queue = Arc::new(MessageQueue::with_clock(None, None, clock.clone()));
+
+        let waiter_queue = message_queue.clone();
+        let waiter = thread::spawn(move || waiter_queue.get_message_timeout(Duration::from_secs(60)).is_none());
+
+        //an hour of virtual time must not take an hour
+        let start = Instant::now();
+        while !waiter.is_finished() && start.elapsed() < Duration::from_secs(5) {
+            clock.advance(Duration::from_secs(60));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(waiter.join().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn stop_message_ends_the_queue() {
+        let mut executor = DeterministicExecutor::new();
+        let queue_a = executor.new_queue("a");
+        let queue_b = executor.new_queue("b");
+        queue_a.register_message_handler(1, Arc::new(ForwardHandler {}));
+        queue_b.register_message_handler(2, Arc::new(ForwardHandler {}));
+
+        queue_a.post_message(Some(Box::new(StepMessage { handler_id: 1, forward_to: None })));
+        queue_a.post_message(None);
+        queue_a.post_message(Some(Box::new(StepMessage { handler_id: 1, forward_to: None })));
+        queue_b.post_message(Some(Box::new(StepMessage { handler_id: 2, forward_to: None })));
+
+        assert_eq!(executor.run_until_idle(), 2);
+        assert!(executor.is_stopped(0));
+        assert!(!executor.is_stopped(1));
+        //what was posted after stop stays in the queue, as it would with a MessageThread
+        assert_eq!(queue_a.inner().pending_count(), 1);
+        queue_b.post_message(Some(Box::new(StepMessage { handler_id: 2, forward_to: None })));
+        assert_eq!(executor.run_until_idle(), 1);
+        assert_eq!(executor.records().last().unwrap().queue_name.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn idle_handlers_run_when_nothing_is_deliverable() {
+        let mut executor = DeterministicExecutor::new();
+        let message_queue = executor.new_queue("a");
+        message_queue.register_message_handler(1, Arc::new(ForwardHandler {}));
+
+        //posts a message the first two times it is idle
+        let idle_count = Arc::new(AtomicUsize::new(0));
+        let idle_queue = message_queue.clone();
+        let count = idle_count.clone();
+        message_queue.add_idle_handler(Box::new(move || {
+            assert!(MessageQueue::current().is_some());
+            if count.fetch_add(1, Ordering::SeqCst) < 2 {
+                idle_queue.post_message(Some(Box::new(StepMessage { handler_id: 1, forward_to: None })));
+            }
+            true
+        }));
+
+        assert_eq!(executor.run_until_idle(), 2);
+        assert_eq!(idle_count.load(Ordering::SeqCst), 3);
+        //once per wait, nothing was dispatched since
+        assert_eq!(executor.run_until_idle(), 0);
+        assert_eq!(idle_count.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::{Mutex, Arc, Weak, Condvar};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use crate::generic;
+use crate::message_queue::MessageQueue;
+
+
+/**
+ *  SweepExpired
+ **/
+pub trait SweepExpired {
+    fn sweep_expired(&self) -> usize;
+}
+
+impl<M: Send + 'static> SweepExpired for generic::MessageQueue<M> {
+    fn sweep_expired(&self) -> usize {
+        generic::MessageQueue::sweep_expired(self)
+    }
+}
+
+impl SweepExpired for MessageQueue {
+    fn sweep_expired(&self) -> usize {
+        MessageQueue::sweep_expired(self)
+    }
+}
+
+
+/**
+ *  ExpirySweeper
+ *
+ *  Expired messages are normally dropped when a reader reaches them. A queue whose
+ *  thread is busy elsewhere, or held back by a sync barrier, would keep them alive,
+ *  so this thread calls sweep_expired() on every added queue once per interval.
+ *  Queues are held weakly and forgotten once dropped.
+ **/
+pub struct ExpirySweeper {
+    queues: Arc<Mutex<Vec<Weak<dyn SweepExpired + Send + Sync>>>>,
+    stop_pair: Arc<(Mutex<bool>, Condvar)>,
+    swept_count: Arc<AtomicUsize>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ExpirySweeper {
+    pub fn new(interval: Duration) -> Self {
+        let queues: Arc<Mutex<Vec<Weak<dyn SweepExpired + Send + Sync>>>> = Arc::new(Mutex::new(Vec::new()));
+        let stop_pair = Arc::new((Mutex::new(false), Condvar::new()));
+        let swept_count = Arc::new(AtomicUsize::new(0));
+
+        let thread_queues = queues.clone();
+        let thread_stop_pair = stop_pair.clone();
+        let thread_swept_count = swept_count.clone();
+        let thread = thread::Builder::new().name("expiry-sweeper".to_string()).spawn(move || {
+            let (stop_mutex, stop_cond) = &*thread_stop_pair;
+            let mut stopped = stop_mutex.lock().unwrap();
+            while !*stopped {
+                stopped = stop_cond.wait_timeout(stopped, interval).unwrap().0;
+                if *stopped {
+                    break;
+                }
+
+                //sweep without holding the stop lock, stop() must not wait for a sweep to start
+                drop(stopped);
+                let live_queues: Vec<Arc<dyn SweepExpired + Send + Sync>> = {
+                    let mut queues = thread_queues.lock().unwrap();
+                    queues.retain(|queue| queue.strong_count() > 0);
+                    queues.iter().filter_map(|queue| queue.upgrade()).collect()
+                };
+                for queue in live_queues {
+                    thread_swept_count.fetch_add(queue.sweep_expired(), Ordering::Relaxed);
+                }
+                stopped = stop_mutex.lock().unwrap();
+            }
+        }).unwrap();
+
+        Self {
+            queues,
+            stop_pair,
+            swept_count,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn add_queue<Q: SweepExpired + Send + Sync + 'static>(&self, queue: &Arc<Q>) {
+        let queue: Arc<dyn SweepExpired + Send + Sync> = queue.clone();
+        self.queues.lock().unwrap().push(Arc::downgrade(&queue));
+    }
+
+    //expired messages removed by this sweeper so far
+    pub fn swept_count(&self) -> usize {
+        self.swept_count.load(Ordering::Relaxed)
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let (stop_mutex, stop_cond) = &*self.stop_pair;
+            *stop_mutex.lock().unwrap() = true;
+            stop_cond.notify_all();
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl Drop for ExpirySweeper {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::any::Any;
+    use std::time::Instant;
+    use crate::message_queue::Message;
+
+    struct TtlMessage {
+    }
+
+    impl Message for TtlMessage {
+        fn handler_id(&self) -> i32 {
+            1
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn sweeps_queues_nobody_reads() {
+        let message_queue = Arc::new(MessageQueue::new());
+        let typed_queue = Arc::new(generic::MessageQueue::<u32>::new());
+        let mut sweeper = ExpirySweeper::new(Duration::from_millis(5));
+        sweeper.add_queue(&message_queue);
+        sweeper.add_queue(&typed_queue);
+
+        for _ in 0..3 {
+            message_queue.post_message_with_ttl(Box::new(TtlMessage {}), Duration::from_millis(1));
+        }
+        message_queue.post_message(Some(Box::new(TtlMessage {})));
+        typed_queue.post_message_with_deadline(7, Instant::now());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while sweeper.swept_count() < 4 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        sweeper.stop();
+        assert_eq!(sweeper.swept_count(), 4);
+        assert_eq!(message_queue.expired_count(), 3);
+        assert_eq!(message_queue.take_messages().len(), 1);
+        assert_eq!(typed_queue.expired_count(), 1);
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::{Mutex, Arc};
+use std::time::Duration;
+
+
+pub const FD_EVENT_INPUT: u32 = libc::EPOLLIN as u32;
+pub const FD_EVENT_OUTPUT: u32 = libc::EPOLLOUT as u32;
+pub const FD_EVENT_ERROR: u32 = libc::EPOLLERR as u32;
+pub const FD_EVENT_HANGUP: u32 = libc::EPOLLHUP as u32;
+
+/**
+ *  FdCallback
+ *  called on the MessageThread with (fd, ready events), return false to unregister the fd
+ **/
+pub type FdCallback = Box<dyn FnMut(RawFd, u32) -> bool + Send>;
+
+
+/**
+ *  FdEventSources
+ *
+ *  One epoll instance watching the registered fds plus an eventfd that
+ *  MessageQueue::post_message() writes to, so a single epoll_wait() wakes up
+ *  for either a posted message or fd readiness (like Android's Looper).
+ **/
+pub struct FdEventSources {
+    epoll_fd: RawFd,
+    wake_fd: RawFd,
+    callbacks_mutex: Mutex<HashMap<RawFd, Arc<Mutex<FdCallback>>>>,
+}
+
+impl FdEventSources {
+    pub fn new() -> io::Result<Self> {
+        unsafe {
+            let epoll_fd = libc::epoll_create1(libc::EPOLL_CLOEXEC);
+            if epoll_fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let wake_fd = libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC);
+            if wake_fd < 0 {
+                let e = io::Error::last_os_error();
+                libc::close(epoll_fd);
+                return Err(e);
+            }
+
+            let fd_event_sources = Self {
+                epoll_fd,
+                wake_fd,
+                callbacks_mutex: Mutex::new(HashMap::new()),
+            };
+            fd_event_sources.epoll_ctl(libc::EPOLL_CTL_ADD, wake_fd, FD_EVENT_INPUT)?;
+            Ok(fd_event_sources)
+        }
+    }
+
+    fn epoll_ctl(&self, op: libc::c_int, fd: RawFd, events: u32) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events,
+            u64: fd as u64,
+        };
+        if unsafe { libc::epoll_ctl(self.epoll_fd, op, fd, &mut event) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn add_fd(&self, fd: RawFd, events: u32, callback: FdCallback) -> io::Result<()> {
+        let mut callbacks = self.callbacks_mutex.lock().unwrap();
+        if callbacks.contains_key(&fd) {
+            self.epoll_ctl(libc::EPOLL_CTL_MOD, fd, events)?;
+        } else {
+            self.epoll_ctl(libc::EPOLL_CTL_ADD, fd, events)?;
+        }
+        callbacks.insert(fd, Arc::new(Mutex::new(callback)));
+        Ok(())
+    }
+
+    pub fn remove_fd(&self, fd: RawFd) -> io::Result<()> {
+        let mut callbacks = self.callbacks_mutex.lock().unwrap();
+        if callbacks.remove(&fd).is_none() {
+            return Ok(());
+        }
+        self.epoll_ctl(libc::EPOLL_CTL_DEL, fd, 0)
+    }
+
+    pub fn wake(&self) {
+        let one: u64 = 1;
+        unsafe {
+            libc::write(self.wake_fd, &one as *const u64 as *const libc::c_void, 8);
+        }
+    }
+
+    /**
+     *  Wait for fd readiness or a wake(), running the callbacks of ready fds.
+     *  Returns after the first batch of events, the caller re-checks its queue.
+     **/
+    pub fn poll(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout_ms = match timeout {
+            Some(dur) => dur.as_millis().min(i32::MAX as u128) as libc::c_int,
+            None => -1,
+        };
+
+        let mut events: [libc::epoll_event; 16] = unsafe { std::mem::zeroed() };
+        let n = unsafe { libc::epoll_wait(self.epoll_fd, events.as_mut_ptr(), events.len() as libc::c_int, timeout_ms) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(e);
+        }
+
+        for event in events.iter().take(n as usize) {
+            let fd = event.u64 as RawFd;
+            let ready = event.events;
+            if fd == self.wake_fd {
+                let mut counter: u64 = 0;
+                unsafe {
+                    libc::read(self.wake_fd, &mut counter as *mut u64 as *mut libc::c_void, 8);
+                }
+                continue;
+            }
+
+            //run the callback without holding the map so it may add or remove fds
+            let callback_option = self.callbacks_mutex.lock().unwrap().get(&fd).cloned();
+            if let Some(callback) = callback_option {
+                let keep = (*callback.lock().unwrap())(fd, ready);
+                if !keep {
+                    self.remove_fd(fd)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FdEventSources {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.wake_fd);
+            libc::close(self.epoll_fd);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_queue::*;
+    use std::any::Any;
+    use std::sync::mpsc;
+
+    struct PingMessage {
+    }
+
+    impl Message for PingMessage {
+        fn handler_id(&self) -> i32 {
+            1
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct PingHandler {
+        tx: Mutex<mpsc::Sender<String>>,
+    }
+
+    impl MessageHandler for PingHandler {
+        fn on_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+            if option_box_msg.is_none() {
+                return false;
+            }
+            self.tx.lock().unwrap().send("message".to_string()).unwrap();
+            true
+        }
+    }
+
+    #[test]
+    fn one_thread_serves_messages_and_fds() {
+        let (tx, rx) = mpsc::channel();
+        let message_queue = Arc::new(MessageQueue::new());
+        message_queue.register_message_handler(1, Arc::new(PingHandler { tx: Mutex::new(tx.clone()) }));
+
+        let mut message_thread = MessageThread::new(message_queue.clone());
+        message_thread.start();
+
+        //registered after start: the thread is already blocked on the condvar
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let fd_tx = Mutex::new(tx);
+        message_thread.add_fd_event_source(fds[0], FD_EVENT_INPUT, Box::new(move |fd, _events| {
+            let mut byte = 0u8;
+            unsafe {
+                libc::read(fd, &mut byte as *mut u8 as *mut libc::c_void, 1);
+            }
+            fd_tx.lock().unwrap().send(format!("fd {}", byte)).unwrap();
+            byte != 0
+        })).unwrap();
+
+        for byte in [7u8, 0u8].iter() {
+            message_queue.post_message(Some(Box::new(PingMessage {})));
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "message");
+            unsafe {
+                libc::write(fds[1], byte as *const u8 as *const libc::c_void, 1);
+            }
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), format!("fd {}", byte));
+        }
+
+        //callback returned false on 0, so the fd is no longer watched
+        unsafe {
+            libc::write(fds[1], [9u8].as_ptr() as *const libc::c_void, 1);
+        }
+        message_queue.post_message(Some(Box::new(PingMessage {})));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "message");
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        message_thread.stop();
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+#![allow(clippy::missing_safety_doc)]
+//C API over MessageQueue, include/msgq.h is generated from this file by
+//build.sh header. Handles come from the *_create/*_start/*_receive functions and
+//must be passed back to their *_destroy/*_stop/*_free exactly once. Pointer
+//arguments may be NULL only where noted.
+
+use std::any::Any;
+use std::ffi::{c_void, CStr};
+use std::os::raw::c_char;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::message_queue::*;
+
+
+/**
+ *  BytesMessage
+ *  what the C API posts and receives: a handler id and a copy of the payload
+ **/
+pub struct BytesMessage {
+    handler_id: i32,
+    data: Vec<u8>,
+}
+
+impl BytesMessage {
+    pub fn new(handler_id: i32, data: Vec<u8>) -> Self {
+        Self {
+            handler_id,
+            data,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Message for BytesMessage {
+    fn handler_id(&self) -> i32 {
+        self.handler_id
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+
+//returns true if the message was handled, false stops the MessageThread like a
+//Rust MessageHandler returning false. data is only valid during the call
+pub type MsgqHandlerFn = Option<extern "C" fn(handler_id: i32, data: *const u8, len: usize, user_data: *mut c_void) -> bool>;
+
+struct CMessageHandler {
+    callback: extern "C" fn(i32, *const u8, usize, *mut c_void) -> bool,
+    user_data: *mut c_void,
+}
+
+//user_data is handed to the MessageThread, the C side keeps it valid and thread safe
+unsafe impl Send for CMessageHandler {}
+unsafe impl Sync for CMessageHandler {}
+
+impl MessageHandler for CMessageHandler {
+    fn on_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+        let box_msg = match option_box_msg {
+            Some(box_msg) => box_msg,
+            None => return false,
+        };
+        //messages posted from Rust that are not BytesMessage arrive without payload
+        let data = box_msg.as_any().downcast_ref::<BytesMessage>().map_or(&[][..], |bytes_msg| bytes_msg.data());
+        let data_ptr = if data.is_empty() { ptr::null() } else { data.as_ptr() };
+        (self.callback)(box_msg.handler_id(), data_ptr, data.len(), self.user_data)
+    }
+}
+
+
+/**
+ *  MsgqQueue
+ **/
+pub struct MsgqQueue {
+    message_queue: Arc<MessageQueue>,
+}
+
+impl MsgqQueue {
+    //a queue shared with Rust code, e.g. one already served by a MessageThread
+    pub fn from_message_queue(message_queue: Arc<MessageQueue>) -> Self {
+        Self {
+            message_queue,
+        }
+    }
+}
+
+/**
+ *  MsgqThread
+ **/
+pub struct MsgqThread {
+    message_thread: MessageThread,
+}
+
+/**
+ *  MsgqMessage
+ **/
+pub struct MsgqMessage {
+    box_msg: Box<dyn Message + Send>,
+}
+
+
+unsafe fn as_queue<'a>(queue: *const MsgqQueue) -> Option<&'a MsgqQueue> {
+    queue.as_ref()
+}
+
+//name may be NULL. Returns NULL if name is not valid UTF-8
+#[no_mangle]
+pub unsafe extern "C" fn msgq_queue_create(name: *const c_char) -> *mut MsgqQueue {
+    let name = if name.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(name).to_str() {
+            Ok(name) => Some(name),
+            Err(_) => return ptr::null_mut(),
+        }
+    };
+    let message_queue = Arc::new(MessageQueue::with_options(name, None));
+    Box::into_raw(Box::new(MsgqQueue::from_message_queue(message_queue)))
+}
+
+//threads started on queue keep it alive until they are stopped. NULL is ignored
+#[no_mangle]
+pub unsafe extern "C" fn msgq_queue_destroy(queue: *mut MsgqQueue) {
+    if !queue.is_null() {
+        drop(Box::from_raw(queue));
+    }
+}
+
+//copies len bytes of data, data may be NULL if len is 0. Returns 0, or -1 on bad arguments
+#[no_mangle]
+pub unsafe extern "C" fn msgq_queue_post(queue: *const MsgqQueue, handler_id: i32, data: *const u8, len: usize) -> i32 {
+    let queue = match as_queue(queue) {
+        Some(queue) => queue,
+        None => return -1,
+    };
+    let data = if len == 0 {
+        Vec::new()
+    } else if data.is_null() {
+        return -1;
+    } else {
+        slice::from_raw_parts(data, len).to_vec()
+    };
+    queue.message_queue.post_message(Some(Box::new(BytesMessage::new(handler_id, data))));
+    0
+}
+
+//posts the stop message: one MessageThread or one msgq_queue_receive() caller returns
+#[no_mangle]
+pub unsafe extern "C" fn msgq_queue_post_stop(queue: *const MsgqQueue) -> i32 {
+    match as_queue(queue) {
+        Some(queue) => {
+            queue.message_queue.post_message(None);
+            0
+        }
+        None => -1,
+    }
+}
+
+//blocks until a message arrives, NULL for the stop message. Free the result with msgq_message_free()
+#[no_mangle]
+pub unsafe extern "C" fn msgq_queue_receive(queue: *const MsgqQueue) -> *mut MsgqMessage {
+    match as_queue(queue).and_then(|queue| queue.message_queue.get_message()) {
+        Some(box_msg) => Box::into_raw(Box::new(MsgqMessage { box_msg })),
+        None => ptr::null_mut(),
+    }
+}
+
+//like msgq_queue_receive(), NULL also when nothing arrived within timeout_ms
+#[no_mangle]
+pub unsafe extern "C" fn msgq_queue_receive_timeout(queue: *const MsgqQueue, timeout_ms: u32) -> *mut MsgqMessage {
+    let timeout = Duration::from_millis(timeout_ms as u64);
+    match as_queue(queue).and_then(|queue| queue.message_queue.get_message_timeout(timeout)) {
+        Some(box_msg) => Box::into_raw(Box::new(MsgqMessage { box_msg })),
+        None => ptr::null_mut(),
+    }
+}
+
+//callback runs on the queue's MessageThread with user_data. Returns 0, -1 on bad arguments
+#[no_mangle]
+pub unsafe extern "C" fn msgq_queue_register_handler(queue: *const MsgqQueue, handler_id: i32, callback: MsgqHandlerFn, user_data: *mut c_void) -> i32 {
+    match (as_queue(queue), callback) {
+        (Some(queue), Some(callback)) => {
+            queue.message_queue.register_message_handler(handler_id, Arc::new(CMessageHandler { callback, user_data }));
+            0
+        }
+        _ => -1,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn msgq_message_handler_id(message: *const MsgqMessage) -> i32 {
+    match message.as_ref() {
+        Some(message) => message.box_msg.handler_id(),
+        None => -1,
+    }
+}
+
+//payload of message, valid until msgq_message_free(). NULL with *len 0 if there is none
+#[no_mangle]
+pub unsafe extern "C" fn msgq_message_data(message: *const MsgqMessage, len: *mut usize) -> *const u8 {
+    let data = message.as_ref()
+        .and_then(|message| message.box_msg.as_any().downcast_ref::<BytesMessage>())
+        .map_or(&[][..], |bytes_msg| bytes_msg.data());
+    if !len.is_null() {
+        *len = data.len();
+    }
+    if data.is_empty() { ptr::null() } else { data.as_ptr() }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn msgq_message_free(message: *mut MsgqMessage) {
+    if !message.is_null() {
+        drop(Box::from_raw(message));
+    }
+}
+
+//starts a MessageThread dispatching queue to the registered handlers
+#[no_mangle]
+pub unsafe extern "C" fn msgq_thread_start(queue: *const MsgqQueue) -> *mut MsgqThread {
+    let queue = match as_queue(queue) {
+        Some(queue) => queue,
+        None => return ptr::null_mut(),
+    };
+    let mut message_thread = MessageThread::new(queue.message_queue.clone());
+    message_thread.start();
+    Box::into_raw(Box::new(MsgqThread { message_thread }))
+}
+
+//posts the stop message, joins the thread and frees thread. NULL is ignored
+#[no_mangle]
+pub unsafe extern "C" fn msgq_thread_stop(thread: *mut MsgqThread) {
+    if !thread.is_null() {
+        let mut thread = Box::from_raw(thread);
+        thread.message_thread.stop();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    extern "C" fn collect(handler_id: i32, data: *const u8, len: usize, user_data: *mut c_void) -> bool {
+        let received = unsafe { &*(user_data as *const Mutex<Vec<(i32, Vec<u8>)>>) };
+        let data = if data.is_null() { Vec::new() } else { unsafe { slice::from_raw_parts(data, len) }.to_vec() };
+        received.lock().unwrap().push((handler_id, data));
+        true
+    }
+
+    #[test]
+    fn post_and_receive_bytes() {
+        unsafe {
+            let queue = msgq_queue_create(b"ffi\0".as_ptr() as *const c_char);
+            assert_eq!(msgq_queue_post(queue, 7, b"hello".as_ptr(), 5), 0);
+            assert_eq!(msgq_queue_post(queue, 8, ptr::null(), 0), 0);
+            assert_eq!(msgq_queue_post(queue, 8, ptr::null(), 1), -1);
+
+            let message = msgq_queue_receive(queue);
+            let mut len = 0;
+            let data = msgq_message_data(message, &mut len);
+            assert_eq!(msgq_message_handler_id(message), 7);
+            assert_eq!(slice::from_raw_parts(data, len), b"hello");
+            msgq_message_free(message);
+
+            let message = msgq_queue_receive_timeout(queue, 10);
+            assert!(msgq_message_data(message, &mut len).is_null());
+            assert_eq!(len, 0);
+            msgq_message_free(message);
+            assert!(msgq_queue_receive_timeout(queue, 10).is_null());
+
+            msgq_queue_post_stop(queue);
+            assert!(msgq_queue_receive(queue).is_null());
+            msgq_queue_destroy(queue);
+        }
+    }
+
+    #[test]
+    fn c_handlers_run_on_message_thread() {
+        let received: Mutex<Vec<(i32, Vec<u8>)>> = Mutex::new(Vec::new());
+        unsafe {
+            let queue = msgq_queue_create(ptr::null());
+            let user_data = &received as *const _ as *mut c_void;
+            assert_eq!(msgq_queue_register_handler(queue, 1, Some(collect), user_data), 0);
+            assert_eq!(msgq_queue_register_handler(queue, 2, None, user_data), -1);
+
+            let thread = msgq_thread_start(queue);
+            msgq_queue_post(queue, 1, b"a".as_ptr(), 1);
+            msgq_queue_post(queue, 1, b"bc".as_ptr(), 2);
+            msgq_thread_stop(thread);
+            msgq_queue_destroy(queue);
+        }
+        assert_eq!(*received.lock().unwrap(), vec![(1, b"a".to_vec()), (1, b"bc".to_vec())]);
+    }
+}
@@ -0,0 +1,1689 @@
+use std::sync::{Arc, Weak, OnceLock};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, VecDeque};
+use std::any::Any;
+use std::cell::RefCell;
+#[cfg(target_os = "linux")]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::RawFd;
+#[cfg(target_os = "linux")]
+use crate::fd_event_source::*;
+use crate::cancellation::{self, CancelListener, CancellationToken};
+use crate::work_stealing::WorkStealingGroup;
+use crate::introspection::{PendingMessageInfo, QueueSnapshot, WorkerStatus};
+use crate::clock::{Clock, ClockListener, SystemClock};
+use serde::{Serialize, Deserialize};
+use crate::sync::{thread, Mutex, RwLock, Condvar, AtomicBool, AtomicI32, AtomicUsize};
+
+
+//Statically typed message queue: M is carried by value, so a queue of a plain
+//enum or struct needs no Box and no Any downcast per message.
+//message_queue::MessageQueue is this queue with M = Box<dyn Message + Send>.
+
+/**
+ *  MessageMeta
+ *  queue-side information about a message, given when it is posted
+ **/
+#[derive(Clone, Debug, Default)]
+pub struct MessageMeta {
+    //asynchronous messages are still delivered while a sync barrier is posted
+    pub asynchronous: bool,
+    //once cancelled, the message is dropped instead of delivered
+    pub cancellation_token: Option<CancellationToken>,
+    //not delivered after this, it goes to the expiry callback instead
+    pub deadline: Option<Instant>,
+    //key for HandlerLimit, message_queue::MessageQueue sets Message::handler_id()
+    pub handler_id: Option<i32>,
+    //never stolen by another thread of a WorkStealingGroup
+    pub thread_affine: bool,
+    //filled in by post_message_with_meta() when not given
+    pub posted_at: Option<Instant>,
+}
+
+impl MessageMeta {
+    fn is_cancelled(&self) -> bool {
+        self.cancellation_token.as_ref().is_some_and(|token| token.is_cancelled())
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= now)
+    }
+}
+
+/**
+ *  IdleHandler
+ *  run when the queue has nothing to deliver, return false to unregister
+ **/
+pub type IdleHandler = Box<dyn FnMut() -> bool + Send>;
+
+/**
+ *  ExpiryCallback
+ *  gets the messages whose deadline passed before they were delivered
+ **/
+pub type ExpiryCallback<M> = Arc<dyn Fn(M) + Send + Sync>;
+
+/**
+ *  HandlerLimit
+ *
+ *  Messages of a limited handler_id that would go over the limit stay in the queue
+ *  and later messages of other handlers are delivered past them.
+ **/
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HandlerLimit {
+    //token bucket refilled at rate_per_second, holding up to burst tokens (at least 1)
+    pub rate_per_second: Option<f64>,
+    pub burst: usize,
+    //messages of the handler dispatched at the same time, over all threads of the queue
+    pub max_concurrent: Option<usize>,
+}
+
+struct HandlerLimiter {
+    limit: HandlerLimit,
+    tokens: f64,
+    refilled_at: Instant,
+    running: usize,
+}
+
+impl HandlerLimiter {
+    fn new(limit: HandlerLimit, running: usize, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst.max(1) as f64,
+            refilled_at: now,
+            running,
+        }
+    }
+
+    fn is_ready(&mut self, now: Instant, retry_at: &mut Option<Instant>) -> bool {
+        if self.limit.max_concurrent.is_some_and(|max_concurrent| self.running >= max_concurrent) {
+            //finish_message() wakes the waiters
+            return false;
+        }
+
+        if let Some(rate_per_second) = self.limit.rate_per_second {
+            let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate_per_second).min(self.limit.burst.max(1) as f64);
+            self.refilled_at = now;
+            if self.tokens < 1.0 {
+                let ready_at = now + Duration::from_secs_f64((1.0 - self.tokens) / rate_per_second);
+                *retry_at = Some(retry_at.map_or(ready_at, |retry_at| retry_at.min(ready_at)));
+                return false;
+            }
+        }
+        true
+    }
+
+    fn acquire(&mut self) {
+        if self.limit.rate_per_second.is_some() {
+            self.tokens -= 1.0;
+        }
+        self.running += 1;
+    }
+}
+
+#[derive(Default)]
+struct HandlerLimiters {
+    limiters: HashMap<i32, HandlerLimiter>,
+    now: Option<Instant>,
+    //earliest time a rate limited message may go, from the last take_next()
+    retry_at: Option<Instant>,
+}
+
+impl HandlerLimiters {
+    fn is_ready(&mut self, meta: &MessageMeta) -> bool {
+        let limiters = &mut self.limiters;
+        let limiter = match meta.handler_id.and_then(|handler_id| limiters.get_mut(&handler_id)) {
+            Some(limiter) => limiter,
+            None => return true,
+        };
+        let now = self.now.expect("now is set by take_next()");
+        limiter.is_ready(now, &mut self.retry_at)
+    }
+}
+
+/**
+ *  FairnessMode
+ *  how the next message is picked between handler ids, see MessageQueueVector::set_fairness()
+ **/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FairnessMode {
+    //post order, a chatty handler_id may starve the others
+    #[default]
+    Fifo,
+    //one message per handler_id in turn
+    RoundRobin,
+    //up to the handler's weight (1 if not set) messages per handler_id in turn
+    WeightedDeficitRoundRobin,
+}
+
+/**
+ *  HandlerWaitStats
+ *  time from post to dequeue of the messages of one handler_id
+ **/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HandlerWaitStats {
+    pub handler_id: Option<i32>,
+    pub dispatched: u64,
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+}
+
+impl HandlerWaitStats {
+    pub fn mean_wait(&self) -> Duration {
+        if self.dispatched == 0 {
+            return Duration::ZERO;
+        }
+        self.total_wait.div_f64(self.dispatched as f64)
+    }
+}
+
+//every handler_id with a deliverable message is a subqueue of its own (its
+//messages keep their order), the subqueues take turns by deficit round-robin
+#[derive(Default)]
+struct FairScheduler {
+    mode: FairnessMode,
+    weights: HashMap<i32, u32>,
+    //subqueues in turn order, the front one is being served
+    order: VecDeque<Option<i32>>,
+    deficits: HashMap<Option<i32>, u64>,
+    in_turn: bool,
+    wait_stats: HashMap<Option<i32>, HandlerWaitStats>,
+}
+
+impl FairScheduler {
+    fn quantum(&self, handler_id: Option<i32>) -> u64 {
+        match (self.mode, handler_id) {
+            (FairnessMode::WeightedDeficitRoundRobin, Some(handler_id)) => self.weights.get(&handler_id).map_or(1, |weight| (*weight).max(1) as u64),
+            _ => 1,
+        }
+    }
+
+    //candidates: index of the first deliverable message of each handler_id, not empty
+    fn pick(&mut self, candidates: &[(Option<i32>, usize)]) -> usize {
+        for (handler_id, _) in candidates {
+            if !self.order.contains(handler_id) {
+                self.order.push_back(*handler_id);
+            }
+        }
+
+        loop {
+            let handler_id = *self.order.front().expect("candidates are queued");
+            let index = match candidates.iter().find(|(candidate, _)| *candidate == handler_id) {
+                Some((_, index)) => *index,
+                None => {
+                    //nothing deliverable: leaves the round and loses its deficit
+                    self.order.pop_front();
+                    self.deficits.remove(&handler_id);
+                    self.in_turn = false;
+                    continue;
+                }
+            };
+            if !self.in_turn {
+                self.in_turn = true;
+                *self.deficits.entry(handler_id).or_default() += self.quantum(handler_id);
+            }
+            let deficit = self.deficits.entry(handler_id).or_default();
+            if *deficit >= 1 {
+                *deficit -= 1;
+                return index;
+            }
+            self.in_turn = false;
+            self.order.rotate_left(1);
+        }
+    }
+
+    //a picked message was dropped instead of delivered
+    fn refund(&mut self, handler_id: Option<i32>) {
+        if self.mode != FairnessMode::Fifo {
+            *self.deficits.entry(handler_id).or_default() += 1;
+        }
+    }
+
+    //a message taken outside pick(), e.g. by a batch
+    fn charge(&mut self, handler_id: Option<i32>) {
+        if let Some(deficit) = self.deficits.get_mut(&handler_id) {
+            *deficit = deficit.saturating_sub(1);
+        }
+    }
+
+    fn record_wait(&mut self, meta: &MessageMeta, now: Instant) {
+        let wait = meta.posted_at.map_or(Duration::ZERO, |posted_at| now.saturating_duration_since(posted_at));
+        let wait_stats = self.wait_stats.entry(meta.handler_id).or_insert_with(|| HandlerWaitStats {
+            handler_id: meta.handler_id,
+            ..HandlerWaitStats::default()
+        });
+        wait_stats.dispatched += 1;
+        wait_stats.total_wait += wait;
+        wait_stats.max_wait = wait_stats.max_wait.max(wait);
+    }
+}
+
+/**
+ *  MessageHandler
+ **/
+pub trait MessageHandler<M> {
+    fn on_message(&self, message_option: Option<M>) -> bool;
+
+    //above 1, MessageThread hands up to this many pending messages with the same
+    //MessageMeta::handler_id as message to on_messages() together
+    fn batch_size(&self, _message: &M, _meta: &MessageMeta) -> usize {
+        1
+    }
+
+    //one result per message, in order
+    fn on_messages(&self, messages: Vec<M>) -> Vec<bool> {
+        messages.into_iter().map(|message| self.on_message(Some(message))).collect()
+    }
+
+    //false keeps message out of batches, a batch also ends before it
+    fn is_batchable(&self, _message: &M, _meta: &MessageMeta) -> bool {
+        true
+    }
+}
+
+enum QueueEntry<M> {
+    Message(Option<M>, MessageMeta),
+    SyncBarrier(i32),
+}
+
+
+/**
+ *  MessageQueueVector
+ **/
+pub struct MessageQueueVector<M> {
+    messages_mutex: Mutex<VecDeque<QueueEntry<M>>>,
+    cond: Condvar,
+    //post_message() blocks while capacity entries are pending, sync barriers count
+    //too. Cancelled messages are taken out by CancellationToken::cancel()
+    capacity: Option<usize>,
+    not_full_cond: Condvar,
+    interrupted: AtomicBool,
+    next_barrier_token: AtomicI32,
+    idle_handlers_mutex: Mutex<Vec<(i32, IdleHandler)>>,
+    next_idle_handler_id: AtomicI32,
+    expired_count: AtomicUsize,
+    expiry_callback: RwLock<Option<ExpiryCallback<M>>>,
+    //locked after messages_mutex, only looked at once a limit was set
+    limiters_mutex: Mutex<HandlerLimiters>,
+    has_limits: AtomicBool,
+    //locked after limiters_mutex, only looked at once set_fairness() or set_handler_weight() was called
+    fair_mutex: Mutex<FairScheduler>,
+    has_fair: AtomicBool,
+    clock: Arc<dyn Clock>,
+}
+
+impl<M: Send> Default for MessageQueueVector<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Send> MessageQueueVector<M> {
+    pub fn new() -> Self {
+        Self::with_capacity(None)
+    }
+
+    pub fn with_capacity(capacity: Option<usize>) -> Self {
+        Self::with_clock(capacity, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(capacity: Option<usize>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            messages_mutex: Mutex::new(VecDeque::new()),
+            cond: Condvar::new(),
+            capacity,
+            not_full_cond: Condvar::new(),
+            interrupted: AtomicBool::new(false),
+            next_barrier_token: AtomicI32::new(1),
+            idle_handlers_mutex: Mutex::new(Vec::new()),
+            next_idle_handler_id: AtomicI32::new(1),
+            expired_count: AtomicUsize::new(0),
+            expiry_callback: RwLock::new(None),
+            limiters_mutex: Mutex::new(HandlerLimiters::default()),
+            has_limits: AtomicBool::new(false),
+            fair_mutex: Mutex::new(FairScheduler::default()),
+            has_fair: AtomicBool::new(false),
+            clock,
+        }
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    //wakes every waiter, so it looks at its deadline again
+    pub fn wake_waiters(&self) {
+        let _messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        self.cond.notify_all();
+    }
+
+    //a sync barrier holds back everything behind it but asynchronous messages and
+    //the None stop message, a handler over its limit holds back only its own messages.
+    //The stop message waits for held back messages posted before it
+    fn next_index(entries: &VecDeque<QueueEntry<M>>, mut limiters: Option<&mut HandlerLimiters>) -> Option<usize> {
+        let mut barrier_seen = false;
+        let mut held_back = false;
+        for (index, entry) in entries.iter().enumerate() {
+            match entry {
+                QueueEntry::SyncBarrier(_) => barrier_seen = true,
+                QueueEntry::Message(None, _) => return if held_back { None } else { Some(index) },
+                QueueEntry::Message(Some(_), meta) => {
+                    if barrier_seen && !meta.asynchronous {
+                        continue;
+                    }
+                    if let Some(limiters) = limiters.as_deref_mut() {
+                        if !limiters.is_ready(meta) {
+                            held_back = true;
+                            continue;
+                        }
+                    }
+                    return Some(index);
+                }
+            }
+        }
+        None
+    }
+
+    //like next_index(), but the first deliverable message of every handler_id is a
+    //candidate and fair picks between them. The None stop message only goes once
+    //every message posted before it was delivered
+    fn next_fair_index(entries: &VecDeque<QueueEntry<M>>, mut limiters: Option<&mut HandlerLimiters>, fair: &mut FairScheduler) -> Option<usize> {
+        let mut barrier_seen = false;
+        let mut candidates: Vec<(Option<i32>, usize)> = Vec::new();
+        let mut held_back: Vec<Option<i32>> = Vec::new();
+        let mut stop_index = None;
+        for (index, entry) in entries.iter().enumerate() {
+            match entry {
+                QueueEntry::SyncBarrier(_) => barrier_seen = true,
+                QueueEntry::Message(None, _) => {
+                    stop_index = Some(index);
+                    break;
+                }
+                QueueEntry::Message(Some(_), meta) => {
+                    if barrier_seen && !meta.asynchronous {
+                        continue;
+                    }
+                    if candidates.iter().any(|(handler_id, _)| *handler_id == meta.handler_id) || held_back.contains(&meta.handler_id) {
+                        continue;
+                    }
+                    if let Some(limiters) = limiters.as_deref_mut() {
+                        if !limiters.is_ready(meta) {
+                            held_back.push(meta.handler_id);
+                            continue;
+                        }
+                    }
+                    candidates.push((meta.handler_id, index));
+                }
+            }
+        }
+        if candidates.is_empty() {
+            return stop_index.filter(|_| held_back.is_empty());
+        }
+        Some(fair.pick(&candidates))
+    }
+
+    //cancelled messages are dropped here too, in case one slipped past remove_cancelled().
+    //expired ones are moved to expired and handed to expire() once the lock is released
+    fn take_next(&self, entries: &mut VecDeque<QueueEntry<M>>, expired: &mut Vec<M>) -> Option<(Option<M>, MessageMeta)> {
+        let mut limiters_guard = if self.has_limits.load(Ordering::Acquire) {
+            let mut limiters_guard = self.limiters_mutex.lock().unwrap();
+            limiters_guard.now = Some(self.clock.now());
+            limiters_guard.retry_at = None;
+            Some(limiters_guard)
+        } else {
+            None
+        };
+
+        let mut fair_guard = if self.has_fair.load(Ordering::Acquire) {
+            Some(self.fair_mutex.lock().unwrap())
+        } else {
+            None
+        };
+
+        loop {
+            let index = match fair_guard.as_deref_mut() {
+                Some(fair) if fair.mode != FairnessMode::Fifo => Self::next_fair_index(entries, limiters_guard.as_deref_mut(), fair)?,
+                _ => Self::next_index(entries, limiters_guard.as_deref_mut())?,
+            };
+            if self.capacity.is_some() {
+                self.not_full_cond.notify_all();
+            }
+            match entries.remove(index) {
+                Some(QueueEntry::Message(message_option, meta)) if meta.is_cancelled() => {
+                    if let (Some(fair), true) = (fair_guard.as_deref_mut(), message_option.is_some()) {
+                        fair.refund(meta.handler_id);
+                    }
+                    continue;
+                }
+                Some(QueueEntry::Message(Some(message), meta)) if meta.is_expired(self.clock.now()) => {
+                    if let Some(fair) = fair_guard.as_deref_mut() {
+                        fair.refund(meta.handler_id);
+                    }
+                    expired.push(message);
+                    continue;
+                }
+                Some(QueueEntry::Message(message_option, meta)) => {
+                    if let (Some(limiters), Some(handler_id)) = (limiters_guard.as_deref_mut(), meta.handler_id) {
+                        if let Some(limiter) = limiters.limiters.get_mut(&handler_id) {
+                            limiter.acquire();
+                        }
+                    }
+                    if let (Some(fair), true) = (fair_guard.as_deref_mut(), message_option.is_some()) {
+                        fair.record_wait(&meta, self.clock.now());
+                    }
+                    return Some((message_option, meta));
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    fn expire(&self, expired: Vec<M>) {
+        if expired.is_empty() {
+            return;
+        }
+
+        self.expired_count.fetch_add(expired.len(), Ordering::Relaxed);
+        let expiry_callback = self.expiry_callback.read().unwrap().clone();
+        if let Some(expiry_callback) = expiry_callback {
+            for message in expired {
+                expiry_callback(message);
+            }
+        }
+    }
+
+    //outer None: timed out or interrupted
+    fn wait_message(&self, deadline: Option<Instant>, interruptible: bool) -> Option<(Option<M>, MessageMeta)> {
+        let mut expired = Vec::new();
+        let entry = self.wait_message_expiring(deadline, interruptible, &mut expired);
+        self.expire(expired);
+        entry
+    }
+
+    fn wait_message_expiring(&self, deadline: Option<Instant>, interruptible: bool, expired: &mut Vec<M>) -> Option<(Option<M>, MessageMeta)> {
+        let mut idle_handled = false;
+        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        loop {
+            if let Some(entry) = self.take_next(&mut messages_mutex_guard, expired) {
+                return Some(entry);
+            }
+
+            if interruptible && self.interrupted.swap(false, Ordering::SeqCst) {
+                return None;
+            }
+
+            //idle handlers run once per wait, outside the lock so they can post
+            if !idle_handled {
+                idle_handled = true;
+                drop(messages_mutex_guard);
+                self.run_idle_handlers();
+                messages_mutex_guard = self.messages_mutex.lock().unwrap();
+                continue;
+            }
+
+            let now = self.clock.now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return None;
+            }
+            let wake_at = match (deadline, self.limit_retry_at()) {
+                (Some(deadline), Some(retry_at)) => Some(deadline.min(retry_at)),
+                (deadline, retry_at) => deadline.or(retry_at),
+            };
+            match wake_at {
+                None => {
+                    messages_mutex_guard = self.cond.wait(messages_mutex_guard).unwrap();
+                }
+                Some(wake_at) => {
+                    if wake_at > now {
+                        messages_mutex_guard = match self.clock.real_timeout(wake_at) {
+                            Some(timeout) => self.cond.wait_timeout(messages_mutex_guard, timeout).unwrap().0,
+                            None => self.cond.wait(messages_mutex_guard).unwrap(),
+                        };
+                    }
+                }
+            }
+        }
+    }
+
+    //the caller handles the message itself, so it counts as finished right away
+    fn finished(&self, entry: Option<(Option<M>, MessageMeta)>) -> Option<Option<M>> {
+        entry.map(|(message_option, meta)| {
+            self.finish_message(&meta);
+            message_option
+        })
+    }
+
+    pub fn get_message(&self) -> Option<M> {
+        self.finished(self.wait_message(None, false)).flatten()
+    }
+
+    //None when now + timeout is past what Instant holds, e.g. Duration::MAX: wait forever
+    pub fn deadline_after(&self, timeout: Duration) -> Option<Instant> {
+        self.clock.now().checked_add(timeout)
+    }
+
+    pub fn get_message_timeout(&self, dur: Duration) -> Option<M> {
+        self.finished(self.wait_message(self.deadline_after(dur), false)).flatten()
+    }
+
+    pub fn post_message(&self, message_option: Option<M>) {
+        self.post_message_with_meta(message_option, MessageMeta::default());
+    }
+
+    pub fn post_message_with_meta(&self, message_option: Option<M>, mut meta: MessageMeta) {
+        if meta.posted_at.is_none() {
+            meta.posted_at = Some(self.clock.now());
+        }
+        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        //the None stop message never waits, so stop() can't block on a full queue
+        if let (Some(capacity), true) = (self.capacity, message_option.is_some()) {
+            while (*messages_mutex_guard).len() >= capacity && !meta.is_cancelled() {
+                messages_mutex_guard = self.not_full_cond.wait(messages_mutex_guard).unwrap();
+            }
+        }
+        //cancelled before it got in, remove_cancelled() may already have run
+        if meta.is_cancelled() {
+            return;
+        }
+        (*messages_mutex_guard).push_back(QueueEntry::Message(message_option, meta));
+        self.cond.notify_all();
+    }
+
+    //outer None: nothing can be delivered right now
+    pub fn try_get_message(&self) -> Option<Option<M>> {
+        self.finished(self.try_get_message_with_meta())
+    }
+
+    //the *_with_meta and interruptible getters leave the message running for its
+    //HandlerLimit, call finish_message() once it was handled
+
+    pub fn try_get_message_with_meta(&self) -> Option<(Option<M>, MessageMeta)> {
+        let mut expired = Vec::new();
+        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        let entry = self.take_next(&mut messages_mutex_guard, &mut expired);
+        drop(messages_mutex_guard);
+        self.expire(expired);
+        entry
+    }
+
+    //like get_message(), but returns None early once interrupt() is called
+    pub fn get_message_interruptible(&self, deadline: Option<Instant>) -> Option<(Option<M>, MessageMeta)> {
+        self.wait_message(deadline, true)
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.messages_mutex.lock().unwrap().len()
+    }
+
+    /**
+     *  Wait until timeout for one message, then take whatever else is deliverable
+     *  right away, max in total. Empty on timeout or the None stop message.
+     **/
+    pub fn get_messages(&self, max: usize, timeout: Option<Duration>) -> Vec<M> {
+        let mut messages = Vec::new();
+        match self.finished(self.wait_message(timeout.and_then(|timeout| self.deadline_after(timeout)), false)) {
+            Some(Some(message)) => messages.push(message),
+            _ => return messages,
+        }
+
+        let mut expired = Vec::new();
+        let mut taken = Vec::new();
+        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        while messages.len() + taken.len() < max {
+            match self.take_next(&mut messages_mutex_guard, &mut expired) {
+                Some((Some(message), meta)) => taken.push((message, meta)),
+                Some((None, meta)) => {
+                    //leave the stop message for the thread
+                    (*messages_mutex_guard).push_front(QueueEntry::Message(None, meta));
+                    break;
+                }
+                None => break,
+            }
+        }
+        drop(messages_mutex_guard);
+        self.expire(expired);
+        for (message, meta) in taken {
+            self.finish_message(&meta);
+            messages.push(message);
+        }
+        messages
+    }
+
+    /**
+     *  Take up to max more messages of handler_id, in order, past other handlers'
+     *  messages but not past a sync barrier, the stop message, its HandlerLimit or
+     *  one of its messages batchable says no to.
+     **/
+    pub fn take_messages_of<F: Fn(&M, &MessageMeta) -> bool>(&self, handler_id: i32, max: usize, batchable: F) -> Vec<(M, MessageMeta)> {
+        let mut taken = Vec::new();
+        let mut expired = Vec::new();
+        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        let mut limiters_guard = if self.has_limits.load(Ordering::Acquire) {
+            let mut limiters_guard = self.limiters_mutex.lock().unwrap();
+            limiters_guard.now = Some(self.clock.now());
+            Some(limiters_guard)
+        } else {
+            None
+        };
+        //a batch counts against the handler's turn
+        let mut fair_guard = if self.has_fair.load(Ordering::Acquire) {
+            Some(self.fair_mutex.lock().unwrap())
+        } else {
+            None
+        };
+
+        let now = self.clock.now();
+        let mut index = 0;
+        while index < (*messages_mutex_guard).len() && taken.len() < max {
+            match &(*messages_mutex_guard)[index] {
+                QueueEntry::SyncBarrier(_) | QueueEntry::Message(None, _) => break,
+                QueueEntry::Message(Some(message), meta) => {
+                    if meta.handler_id != Some(handler_id) {
+                        index += 1;
+                        continue;
+                    }
+                    if !meta.is_cancelled() && !meta.is_expired(now) {
+                        if !batchable(message, meta) {
+                            break;
+                        }
+                        if let Some(limiters) = limiters_guard.as_deref_mut() {
+                            if !limiters.is_ready(meta) {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            match (*messages_mutex_guard).remove(index) {
+                Some(QueueEntry::Message(_, meta)) if meta.is_cancelled() => {}
+                Some(QueueEntry::Message(Some(message), meta)) if meta.is_expired(now) => expired.push(message),
+                Some(QueueEntry::Message(Some(message), meta)) => {
+                    if let Some(limiter) = limiters_guard.as_deref_mut().and_then(|limiters| limiters.limiters.get_mut(&handler_id)) {
+                        limiter.acquire();
+                    }
+                    if let Some(fair) = fair_guard.as_deref_mut() {
+                        fair.charge(meta.handler_id);
+                        fair.record_wait(&meta, now);
+                    }
+                    taken.push((message, meta));
+                }
+                _ => unreachable!(),
+            }
+        }
+        if !taken.is_empty() {
+            self.not_full_cond.notify_all();
+        }
+        drop(limiters_guard);
+        drop(messages_mutex_guard);
+        self.expire(expired);
+        taken
+    }
+
+    //everything pending, in order, without taking it out
+    pub fn snapshot<F: Fn(&M) -> String>(&self, type_name: F) -> Vec<PendingMessageInfo> {
+        let messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        let now = self.clock.now();
+        (*messages_mutex_guard).iter().map(|entry| match entry {
+            QueueEntry::Message(message_option, meta) => PendingMessageInfo {
+                handler_id: meta.handler_id,
+                type_name: message_option.as_ref().map_or_else(|| String::from("None (stop)"), &type_name),
+                age_ms: meta.posted_at.map_or(0, |posted_at| now.saturating_duration_since(posted_at).as_millis() as u64),
+                asynchronous: meta.asynchronous,
+                thread_affine: meta.thread_affine,
+                cancelled: meta.is_cancelled(),
+                sync_barrier: None,
+            },
+            QueueEntry::SyncBarrier(token) => PendingMessageInfo {
+                type_name: String::from("SyncBarrier"),
+                sync_barrier: Some(*token),
+                ..PendingMessageInfo::default()
+            },
+        }).collect()
+    }
+
+    /**
+     *  Take up to max messages another thread may run: from the head, not thread
+     *  affine, not held back by a sync barrier and not of a limited handler.
+     **/
+    pub fn steal_messages(&self, max: usize) -> Vec<(M, MessageMeta)> {
+        let mut stolen = Vec::new();
+        let mut expired = Vec::new();
+        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        let limiters_guard = if self.has_limits.load(Ordering::Acquire) {
+            Some(self.limiters_mutex.lock().unwrap())
+        } else {
+            None
+        };
+        let is_limited = |meta: &MessageMeta| match (limiters_guard.as_ref(), meta.handler_id) {
+            (Some(limiters), Some(handler_id)) => limiters.limiters.contains_key(&handler_id),
+            _ => false,
+        };
+
+        let now = self.clock.now();
+        let mut index = 0;
+        while index < (*messages_mutex_guard).len() && stolen.len() < max {
+            let stealable = match &(*messages_mutex_guard)[index] {
+                QueueEntry::SyncBarrier(_) => break,
+                QueueEntry::Message(None, _) => break,
+                QueueEntry::Message(Some(_), meta) => !meta.thread_affine && !is_limited(meta),
+            };
+            if !stealable {
+                index += 1;
+                continue;
+            }
+            match (*messages_mutex_guard).remove(index) {
+                Some(QueueEntry::Message(_, meta)) if meta.is_cancelled() => {}
+                Some(QueueEntry::Message(Some(message), meta)) if meta.is_expired(now) => expired.push(message),
+                Some(QueueEntry::Message(Some(message), meta)) => stolen.push((message, meta)),
+                _ => unreachable!(),
+            }
+        }
+        if !stolen.is_empty() {
+            self.not_full_cond.notify_all();
+        }
+        drop(limiters_guard);
+        drop(messages_mutex_guard);
+        self.expire(expired);
+        stolen
+    }
+
+    pub fn interrupt(&self) {
+        let _messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        self.interrupted.store(true, Ordering::SeqCst);
+        self.cond.notify_all();
+    }
+
+    pub fn take_messages(&self) -> Vec<Option<M>> {
+        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        self.not_full_cond.notify_all();
+        std::mem::take(&mut *messages_mutex_guard).into_iter().filter_map(|entry| match entry {
+            QueueEntry::Message(message_option, _) => Some(message_option),
+            QueueEntry::SyncBarrier(_) => None,
+        }).collect()
+    }
+
+    pub fn set_expiry_callback(&self, expiry_callback: Option<ExpiryCallback<M>>) {
+        *self.expiry_callback.write().unwrap() = expiry_callback;
+    }
+
+    pub fn set_handler_limit(&self, handler_id: i32, limit: HandlerLimit) {
+        let _messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        let mut limiters_guard = self.limiters_mutex.lock().unwrap();
+        //messages of the handler already running still count against the new limit
+        let running = limiters_guard.limiters.get(&handler_id).map_or(0, |limiter| limiter.running);
+        limiters_guard.limiters.insert(handler_id, HandlerLimiter::new(limit, running, self.clock.now()));
+        self.has_limits.store(true, Ordering::Release);
+        self.cond.notify_all();
+    }
+
+    pub fn remove_handler_limit(&self, handler_id: i32) -> bool {
+        let _messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        let mut limiters_guard = self.limiters_mutex.lock().unwrap();
+        let removed = limiters_guard.limiters.remove(&handler_id).is_some();
+        self.has_limits.store(!limiters_guard.limiters.is_empty(), Ordering::Release);
+        self.cond.notify_all();
+        removed
+    }
+
+    /**
+     *  Pick the next message by handler_id instead of post order, so one busy
+     *  handler_id can't starve the others. Also turns on handler_wait_stats(),
+     *  FairnessMode::Fifo only collects those.
+     **/
+    pub fn set_fairness(&self, mode: FairnessMode) {
+        let _messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        let mut fair_guard = self.fair_mutex.lock().unwrap();
+        fair_guard.mode = mode;
+        fair_guard.order.clear();
+        fair_guard.deficits.clear();
+        fair_guard.in_turn = false;
+        self.has_fair.store(true, Ordering::Release);
+        self.cond.notify_all();
+    }
+
+    pub fn fairness(&self) -> FairnessMode {
+        self.fair_mutex.lock().unwrap().mode
+    }
+
+    //messages per turn under FairnessMode::WeightedDeficitRoundRobin, at least 1
+    pub fn set_handler_weight(&self, handler_id: i32, weight: u32) {
+        let _messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        let mut fair_guard = self.fair_mutex.lock().unwrap();
+        fair_guard.weights.insert(handler_id, weight.max(1));
+        self.has_fair.store(true, Ordering::Release);
+    }
+
+    //sorted by handler_id, empty until set_fairness() or set_handler_weight()
+    pub fn handler_wait_stats(&self) -> Vec<HandlerWaitStats> {
+        let mut wait_stats: Vec<HandlerWaitStats> = self.fair_mutex.lock().unwrap().wait_stats.values().cloned().collect();
+        wait_stats.sort_by_key(|wait_stats| wait_stats.handler_id);
+        wait_stats
+    }
+
+    //true when a limited handler got a slot back
+    pub fn finish_message(&self, meta: &MessageMeta) -> bool {
+        let handler_id = match meta.handler_id {
+            Some(handler_id) if self.has_limits.load(Ordering::Acquire) => handler_id,
+            _ => return false,
+        };
+
+        let _messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        let mut limiters_guard = self.limiters_mutex.lock().unwrap();
+        match limiters_guard.limiters.get_mut(&handler_id) {
+            Some(limiter) => {
+                limiter.running = limiter.running.saturating_sub(1);
+                self.cond.notify_all();
+                true
+            }
+            None => false,
+        }
+    }
+
+    //when the first rate limited message may be taken, as of the last failed take
+    pub fn limit_retry_at(&self) -> Option<Instant> {
+        if !self.has_limits.load(Ordering::Acquire) {
+            return None;
+        }
+        self.limiters_mutex.lock().unwrap().retry_at
+    }
+
+    pub fn expired_count(&self) -> usize {
+        self.expired_count.load(Ordering::Relaxed)
+    }
+
+    /**
+     *  Remove every expired or cancelled entry, not only the ones at the head, so a
+     *  queue nobody reads from doesn't keep them alive. Returns the expired count.
+     **/
+    pub fn sweep_expired(&self) -> usize {
+        let mut expired = Vec::new();
+        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        let now = self.clock.now();
+        let removable = |entry: &QueueEntry<M>| match entry {
+            QueueEntry::Message(Some(_), meta) => meta.is_cancelled() || meta.is_expired(now),
+            _ => false,
+        };
+        if !(*messages_mutex_guard).iter().any(removable) {
+            return 0;
+        }
+
+        let entries = std::mem::take(&mut *messages_mutex_guard);
+        for entry in entries {
+            match entry {
+                QueueEntry::Message(_, meta) if meta.is_cancelled() => {}
+                QueueEntry::Message(Some(message), meta) if meta.is_expired(now) => expired.push(message),
+                entry => (*messages_mutex_guard).push_back(entry),
+            }
+        }
+        self.not_full_cond.notify_all();
+        drop(messages_mutex_guard);
+
+        let count = expired.len();
+        self.expire(expired);
+        count
+    }
+
+    //drops every message whose CancellationToken was cancelled, returns how many
+    pub fn remove_cancelled(&self) -> usize {
+        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        let len = (*messages_mutex_guard).len();
+        (*messages_mutex_guard).retain(|entry| !matches!(entry, QueueEntry::Message(Some(_), meta) if meta.is_cancelled()));
+        let removed = len - (*messages_mutex_guard).len();
+        if removed > 0 {
+            self.not_full_cond.notify_all();
+        }
+        removed
+    }
+
+    pub fn post_sync_barrier(&self) -> i32 {
+        let token = self.next_barrier_token.fetch_add(1, Ordering::SeqCst);
+        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        (*messages_mutex_guard).push_back(QueueEntry::SyncBarrier(token));
+        token
+    }
+
+    pub fn remove_sync_barrier(&self, token: i32) -> bool {
+        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
+        let index = (*messages_mutex_guard).iter().position(|entry| matches!(entry, QueueEntry::SyncBarrier(t) if *t == token));
+        match index {
+            Some(index) => {
+                (*messages_mutex_guard).remove(index);
+                self.cond.notify_all();
+                self.not_full_cond.notify_all();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn add_idle_handler(&self, idle_handler: IdleHandler) -> i32 {
+        let id = self.next_idle_handler_id.fetch_add(1, Ordering::SeqCst);
+        self.idle_handlers_mutex.lock().unwrap().push((id, idle_handler));
+        id
+    }
+
+    pub fn remove_idle_handler(&self, id: i32) -> bool {
+        let mut idle_handlers = self.idle_handlers_mutex.lock().unwrap();
+        let len = idle_handlers.len();
+        idle_handlers.retain(|(handler_id, _)| *handler_id != id);
+        idle_handlers.len() != len
+    }
+
+    pub fn run_idle_handlers(&self) {
+        //taken out while running, so a handler may add or remove idle handlers
+        let mut idle_handlers = std::mem::take(&mut *self.idle_handlers_mutex.lock().unwrap());
+        if idle_handlers.is_empty() {
+            return;
+        }
+
+        idle_handlers.retain_mut(|(_, idle_handler)| idle_handler());
+        let mut idle_handlers_mutex_guard = self.idle_handlers_mutex.lock().unwrap();
+        idle_handlers.append(&mut idle_handlers_mutex_guard);
+        *idle_handlers_mutex_guard = idle_handlers;
+    }
+}
+
+
+thread_local! {
+    //the queue served by the current MessageThread, as whatever type that thread set
+    static CURRENT_MESSAGE_QUEUE: RefCell<Option<Arc<dyn Any + Send + Sync>>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn current_message_queue() -> Option<Arc<dyn Any + Send + Sync>> {
+    CURRENT_MESSAGE_QUEUE.with(|current| current.borrow().clone())
+}
+
+pub(crate) fn set_current_message_queue(current_option: Option<Arc<dyn Any + Send + Sync>>) {
+    CURRENT_MESSAGE_QUEUE.with(|current| *current.borrow_mut() = current_option);
+}
+
+
+/**
+ *  MessageQueue
+ **/
+pub struct MessageQueue<M> {
+    name: Option<Arc<str>>,
+    message_queue_vector: Arc<MessageQueueVector<M>>,
+    message_handler: Arc<OnceLock<Arc<dyn MessageHandler<M> + Send + Sync>>>,
+    //created on the first registered fd, then the thread loop waits in epoll instead of the condvar
+    #[cfg(target_os = "linux")]
+    fd_event_sources: Arc<OnceLock<FdEventSources>>,
+    //registered with the clock, which only keeps it weakly
+    _clock_waker: Arc<dyn ClockListener + Send + Sync>,
+}
+
+/**
+ *  QueueClockWaker
+ *  wakes whoever waits on the queue, condvar or epoll, when its Clock moves
+ **/
+struct QueueClockWaker<M> {
+    message_queue_vector: Weak<MessageQueueVector<M>>,
+    #[cfg(target_os = "linux")]
+    fd_event_sources: Weak<OnceLock<FdEventSources>>,
+}
+
+impl<M: Send> CancelListener for MessageQueueVector<M> {
+    fn on_cancelled(&self) {
+        self.remove_cancelled();
+    }
+}
+
+impl<M: Send> ClockListener for QueueClockWaker<M> {
+    fn on_clock_advanced(&self) {
+        if let Some(message_queue_vector) = self.message_queue_vector.upgrade() {
+            message_queue_vector.wake_waiters();
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(fd_event_sources) = self.fd_event_sources.upgrade() {
+            if let Some(fd_event_sources) = fd_event_sources.get() {
+                fd_event_sources.wake();
+            }
+        }
+    }
+}
+
+impl<M> Clone for MessageQueue<M> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            message_queue_vector: self.message_queue_vector.clone(),
+            message_handler: self.message_handler.clone(),
+            #[cfg(target_os = "linux")]
+            fd_event_sources: self.fd_event_sources.clone(),
+            _clock_waker: self._clock_waker.clone(),
+        }
+    }
+}
+
+impl<M: Send + 'static> Default for MessageQueue<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Send + 'static> MessageQueue<M> {
+    pub fn new() -> Self {
+        Self::with_options(None, None)
+    }
+
+    pub fn with_options(name: Option<&str>, capacity: Option<usize>) -> Self {
+        Self::with_clock(name, capacity, Arc::new(SystemClock))
+    }
+
+    //e.g. a VirtualClock for tests
+    pub fn with_clock(name: Option<&str>, capacity: Option<usize>, clock: Arc<dyn Clock>) -> Self {
+        let message_queue_vector = Arc::new(MessageQueueVector::with_clock(capacity, clock.clone()));
+        #[cfg(target_os = "linux")]
+        let fd_event_sources = Arc::new(OnceLock::new());
+        let clock_waker: Arc<dyn ClockListener + Send + Sync> = Arc::new(QueueClockWaker {
+            message_queue_vector: Arc::downgrade(&message_queue_vector),
+            #[cfg(target_os = "linux")]
+            fd_event_sources: Arc::downgrade(&fd_event_sources),
+        });
+        clock.add_listener(Arc::downgrade(&clock_waker));
+
+        Self {
+            name: name.map(Arc::from),
+            message_queue_vector,
+            message_handler: Arc::new(OnceLock::new()),
+            #[cfg(target_os = "linux")]
+            fd_event_sources,
+            _clock_waker: clock_waker,
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        self.message_queue_vector.clock()
+    }
+
+    pub fn now(&self) -> Instant {
+        self.message_queue_vector.now()
+    }
+
+    pub fn set_message_handler(&self, handler: Arc<dyn MessageHandler<M> + Send + Sync>) {
+        if self.message_handler.set(handler).is_err() {
+            println!("message handler already exist");
+        }
+    }
+
+    pub fn get_message(&self) -> Option<M> {
+        self.message_queue_vector.get_message()
+    }
+
+    pub fn get_message_timeout(&self, duration: Duration) -> Option<M> {
+        self.message_queue_vector.get_message_timeout(duration)
+    }
+
+    pub fn try_get_message(&self) -> Option<Option<M>> {
+        self.message_queue_vector.try_get_message()
+    }
+
+    //for callers that dispatch by themselves with dispatch_message_with_meta()
+    pub(crate) fn try_get_message_with_meta(&self) -> Option<(Option<M>, MessageMeta)> {
+        self.message_queue_vector.try_get_message_with_meta()
+    }
+
+    pub(crate) fn run_idle_handlers(&self) {
+        self.message_queue_vector.run_idle_handlers();
+    }
+
+    pub fn post_message(&self, message_option: Option<M>) {
+        self.post_message_with_meta(message_option, MessageMeta::default());
+    }
+
+    pub fn post_message_with_meta(&self, message_option: Option<M>, meta: MessageMeta) {
+        if let Some(token) = meta.cancellation_token.as_ref() {
+            let listener: Weak<MessageQueueVector<M>> = Arc::downgrade(&self.message_queue_vector);
+            token.add_listener(listener);
+        }
+        self.message_queue_vector.post_message_with_meta(message_option, meta);
+        self.wake();
+    }
+
+    pub fn post_message_cancellable(&self, message: M) -> CancellationToken {
+        let token = CancellationToken::new();
+        self.post_message_with_token(message, &token);
+        token
+    }
+
+    pub fn post_message_with_deadline(&self, message: M, deadline: Instant) {
+        let meta = MessageMeta {
+            deadline: Some(deadline),
+            ..MessageMeta::default()
+        };
+        self.post_message_with_meta(Some(message), meta);
+    }
+
+    pub fn set_expiry_callback(&self, expiry_callback: Option<ExpiryCallback<M>>) {
+        self.message_queue_vector.set_expiry_callback(expiry_callback);
+    }
+
+    pub fn expired_count(&self) -> usize {
+        self.message_queue_vector.expired_count()
+    }
+
+    pub fn sweep_expired(&self) -> usize {
+        self.message_queue_vector.sweep_expired()
+    }
+
+    //applies to messages posted with MessageMeta::handler_id
+    pub fn set_handler_limit(&self, handler_id: i32, limit: HandlerLimit) {
+        self.message_queue_vector.set_handler_limit(handler_id, limit);
+        self.wake();
+    }
+
+    pub fn remove_handler_limit(&self, handler_id: i32) -> bool {
+        let removed = self.message_queue_vector.remove_handler_limit(handler_id);
+        self.wake();
+        removed
+    }
+
+    pub fn set_fairness(&self, mode: FairnessMode) {
+        self.message_queue_vector.set_fairness(mode);
+        self.wake();
+    }
+
+    pub fn fairness(&self) -> FairnessMode {
+        self.message_queue_vector.fairness()
+    }
+
+    pub fn set_handler_weight(&self, handler_id: i32, weight: u32) {
+        self.message_queue_vector.set_handler_weight(handler_id, weight);
+    }
+
+    pub fn handler_wait_stats(&self) -> Vec<HandlerWaitStats> {
+        self.message_queue_vector.handler_wait_stats()
+    }
+
+    //share one token, or child tokens of it, to cancel a group of messages at once
+    pub fn post_message_with_token(&self, message: M, token: &CancellationToken) {
+        let meta = MessageMeta {
+            cancellation_token: Some(token.clone()),
+            ..MessageMeta::default()
+        };
+        self.post_message_with_meta(Some(message), meta);
+    }
+
+    /**
+     *  The queue the calling MessageThread<M> is serving.
+     **/
+    pub fn current() -> Option<Arc<Self>> {
+        current_message_queue().and_then(|current| current.downcast::<Self>().ok())
+    }
+
+    pub fn is_current(&self) -> bool {
+        match Self::current() {
+            Some(message_queue) => Arc::ptr_eq(&message_queue.message_queue_vector, &self.message_queue_vector),
+            None => false,
+        }
+    }
+
+    pub(crate) fn same_queue(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.message_queue_vector, &other.message_queue_vector)
+    }
+
+    fn wake(&self) {
+        #[cfg(target_os = "linux")]
+        if let Some(fd_event_sources) = self.fd_event_sources.get() {
+            fd_event_sources.wake();
+        }
+    }
+
+    /**
+     *  Hold back every ordinary message posted so far or later until the barrier is
+     *  removed, only messages posted with MessageMeta::asynchronous get through.
+     **/
+    pub fn post_sync_barrier(&self) -> i32 {
+        self.message_queue_vector.post_sync_barrier()
+    }
+
+    pub fn remove_sync_barrier(&self, token: i32) -> bool {
+        let removed = self.message_queue_vector.remove_sync_barrier(token);
+        if removed {
+            self.wake();
+        }
+        removed
+    }
+
+    pub fn add_idle_handler(&self, idle_handler: IdleHandler) -> i32 {
+        self.message_queue_vector.add_idle_handler(idle_handler)
+    }
+
+    pub fn remove_idle_handler(&self, id: i32) -> bool {
+        self.message_queue_vector.remove_idle_handler(id)
+    }
+
+    pub fn take_messages(&self) -> Vec<Option<M>> {
+        self.message_queue_vector.take_messages()
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn add_fd_event_source(&self, fd: RawFd, events: u32, callback: FdCallback) -> io::Result<()> {
+        let mut created = false;
+        let fd_event_sources = match self.fd_event_sources.get() {
+            Some(fd_event_sources) => fd_event_sources,
+            None => {
+                let fd_event_sources = FdEventSources::new()?;
+                created = self.fd_event_sources.set(fd_event_sources).is_ok();
+                self.fd_event_sources.get().unwrap()
+            }
+        };
+
+        fd_event_sources.add_fd(fd, events, callback)?;
+        if created {
+            //a thread already blocked on the condvar has to switch over to epoll
+            self.message_queue_vector.interrupt();
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn remove_fd_event_source(&self, fd: RawFd) -> io::Result<()> {
+        match self.fd_event_sources.get() {
+            Some(fd_event_sources) => fd_event_sources.remove_fd(fd),
+            None => Ok(()),
+        }
+    }
+
+    //None once deadline passed
+    fn wait_next_message(&self, deadline: Option<Instant>) -> Option<(Option<M>, MessageMeta)> {
+        #[cfg(target_os = "linux")]
+        let mut idle_handled = false;
+        loop {
+            #[cfg(target_os = "linux")]
+            if let Some(fd_event_sources) = self.fd_event_sources.get() {
+                //post_message() writes the eventfd after pushing, so checking the
+                //vector before every epoll_wait() can't miss a message
+                if let Some(entry) = self.message_queue_vector.try_get_message_with_meta() {
+                    return Some(entry);
+                }
+                if !idle_handled {
+                    idle_handled = true;
+                    self.message_queue_vector.run_idle_handlers();
+                    continue;
+                }
+                //rate limited messages have to be looked at again once their token is due
+                let now = self.now();
+                if deadline.is_some_and(|deadline| now >= deadline) {
+                    return None;
+                }
+                let wake_at = match (deadline, self.message_queue_vector.limit_retry_at()) {
+                    (Some(deadline), Some(retry_at)) => Some(deadline.min(retry_at)),
+                    (deadline, retry_at) => deadline.or(retry_at),
+                };
+                //a VirtualClock writes the eventfd when it moves
+                let timeout = wake_at.and_then(|wake_at| self.message_queue_vector.clock().real_timeout(wake_at))
+                    .map(|timeout| timeout + Duration::from_millis(1));
+                if let Err(e) = fd_event_sources.poll(timeout) {
+                    println!("MessageQueue: epoll_wait failed {}", e);
+                }
+                continue;
+            }
+
+            if let Some(entry) = self.message_queue_vector.get_message_interruptible(deadline) {
+                return Some(entry);
+            }
+            if deadline.is_some_and(|deadline| self.now() >= deadline) {
+                return None;
+            }
+        }
+    }
+
+    pub fn dispatch_message(&self, message_option: Option<M>) -> bool {
+        match self.message_handler.get() {
+            Some(handler) => handler.on_message(message_option),
+            None => false,
+        }
+    }
+
+    //also used by WorkStealingGroup for messages stolen from this queue
+    pub(crate) fn dispatch_message_with_meta(&self, message_option: Option<M>, meta: MessageMeta) -> bool {
+        let previous_token = cancellation::set_current_cancellation_token(meta.cancellation_token.clone());
+        let ret = self.dispatch_message(message_option);
+        cancellation::set_current_cancellation_token(previous_token);
+        if self.message_queue_vector.finish_message(&meta) {
+            //another thread of this queue may be waiting in epoll for the slot
+            self.wake();
+        }
+        ret
+    }
+
+    pub fn process_next_message(&self) -> bool {
+        self.process_next_message_timeout(None).unwrap_or(false)
+    }
+
+    //None: nothing came before timeout
+    pub fn process_next_message_timeout(&self, timeout: Option<Duration>) -> Option<bool> {
+        self.process_next_message_for(timeout, None)
+    }
+
+    //false ends the MessageThread: the stop message, or a single message its handler
+    //returned false for. A failed message of a batch only shows in its result
+    pub(crate) fn process_next_message_for(&self, timeout: Option<Duration>, worker_status: Option<&WorkerStatus>) -> Option<bool> {
+        self.dispatch_next(timeout, worker_status).map(|(_, keep_going)| keep_going)
+    }
+
+    /**
+     *  Like process_next_message(), but a handler taking batches may get several
+     *  messages at once. One result per dispatched message, empty for the stop message.
+     **/
+    pub fn process_next_messages(&self) -> Vec<bool> {
+        self.dispatch_next(None, None).map(|(results, _)| results).unwrap_or_default()
+    }
+
+    //the results and whether the MessageThread goes on, see process_next_message_for()
+    fn dispatch_next(&self, timeout: Option<Duration>, worker_status: Option<&WorkerStatus>) -> Option<(Vec<bool>, bool)> {
+        let (message_option, meta) = self.wait_next_message(timeout.and_then(|timeout| self.message_queue_vector.deadline_after(timeout)))?;
+        let message = match message_option {
+            Some(message) => message,
+            None => return Some((Vec::new(), false)),
+        };
+
+        if let Some(worker_status) = worker_status {
+            worker_status.begin(meta.handler_id);
+        }
+        let batch_size = match (self.message_handler.get(), meta.handler_id) {
+            (Some(handler), Some(_)) if handler.is_batchable(&message, &meta) => handler.batch_size(&message, &meta),
+            _ => 1,
+        };
+        let (results, keep_going) = if batch_size > 1 {
+            (self.dispatch_batch(message, meta, batch_size), true)
+        } else {
+            let handled = self.dispatch_message_with_meta(Some(message), meta);
+            (vec![handled], handled)
+        };
+        if let Some(worker_status) = worker_status {
+            worker_status.end(results.len() as u64);
+        }
+        Some((results, keep_going))
+    }
+
+    //no CancellationToken::current() here, the messages may have different tokens
+    fn dispatch_batch(&self, message: M, meta: MessageMeta, batch_size: usize) -> Vec<bool> {
+        let handler_id = meta.handler_id.unwrap();
+        let mut messages = vec![message];
+        let mut metas = vec![meta];
+        let batchable = |message: &M, meta: &MessageMeta| self.message_handler.get().is_some_and(|handler| handler.is_batchable(message, meta));
+        for (message, meta) in self.message_queue_vector.take_messages_of(handler_id, batch_size - 1, batchable) {
+            messages.push(message);
+            metas.push(meta);
+        }
+
+        let count = messages.len();
+        let mut results = match self.message_handler.get() {
+            Some(handler) => handler.on_messages(messages),
+            None => vec![false; count],
+        };
+        if results.len() != count {
+            println!("MessageQueue: {} results for a batch of {} messages", results.len(), count);
+            results.resize(count, false);
+        }
+
+        let mut released = false;
+        for meta in metas.iter() {
+            released |= self.message_queue_vector.finish_message(meta);
+        }
+        if released {
+            self.wake();
+        }
+        results
+    }
+
+    pub fn get_messages(&self, max: usize, timeout: Option<Duration>) -> Vec<M> {
+        self.message_queue_vector.get_messages(max, timeout)
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.message_queue_vector.pending_count()
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        self.snapshot_with(|message| libhelper::helper::type_of(message))
+    }
+
+    pub fn snapshot_with<F: Fn(&M) -> String>(&self, type_name: F) -> QueueSnapshot {
+        QueueSnapshot {
+            name: self.name().map(String::from),
+            expired_count: self.expired_count(),
+            pending: self.message_queue_vector.snapshot(type_name),
+        }
+    }
+
+    pub fn steal_messages(&self, max: usize) -> Vec<(M, MessageMeta)> {
+        self.message_queue_vector.steal_messages(max)
+    }
+}
+
+
+/**
+ *  ThreadConfig
+ **/
+#[derive(Clone, Debug, Default)]
+pub struct ThreadConfig {
+    //defaults to the queue name
+    pub name: Option<String>,
+    pub stack_size: Option<usize>,
+    //CPUs the thread may run on, empty for no restriction
+    pub cpu_affinity: Vec<usize>,
+}
+
+//cpu numbers ThreadConfig::cpu_affinity can hold
+#[cfg(target_os = "linux")]
+pub const MAX_CPUS: usize = libc::CPU_SETSIZE as usize;
+#[cfg(not(target_os = "linux"))]
+pub const MAX_CPUS: usize = 1024;
+
+#[cfg(target_os = "linux")]
+fn set_cpu_affinity(cpus: &[usize]) {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus {
+            if *cpu >= MAX_CPUS {
+                println!("MessageThread: cpu {} is out of range, at most {} cpus", cpu, MAX_CPUS);
+                continue;
+            }
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            println!("MessageThread: sched_setaffinity {:?} failed {}", cpus, io::Error::last_os_error());
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_cpu_affinity(cpus: &[usize]) {
+    println!("MessageThread: cpu affinity {:?} is not supported on this platform", cpus);
+}
+
+
+/**
+ *  MessageThread
+ **/
+pub struct MessageThread<M: Send + 'static> {
+    message_queue: Arc<MessageQueue<M>>,
+    //what MessageQueue::current() sees on the thread, normally message_queue itself
+    current: Arc<dyn Any + Send + Sync>,
+    thread_config: ThreadConfig,
+    work_stealing_group: Option<Arc<WorkStealingGroup<M>>>,
+    worker_status: Arc<WorkerStatus>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl<M: Send + 'static> MessageThread<M> {
+    pub fn new(message_queue: Arc<MessageQueue<M>>) -> Self {
+        let current = message_queue.clone();
+        Self::with_current(message_queue, current)
+    }
+
+    pub(crate) fn with_current(message_queue: Arc<MessageQueue<M>>, current: Arc<dyn Any + Send + Sync>) -> Self {
+        Self {
+            message_queue,
+            current,
+            thread_config: ThreadConfig::default(),
+            work_stealing_group: None,
+            worker_status: Arc::new(WorkerStatus::new()),
+            thread: None,
+        }
+    }
+
+    //takes effect on the next start()
+    pub fn set_thread_config(&mut self, thread_config: ThreadConfig) {
+        self.thread_config = thread_config;
+    }
+
+    pub fn thread_name(&self) -> Option<&str> {
+        self.thread_config.name.as_deref().or_else(|| self.message_queue.name())
+    }
+
+    //see DebugDump
+    pub fn worker_status(&self) -> Arc<WorkerStatus> {
+        self.worker_status.clone()
+    }
+
+    //takes effect on the next start(), messages of this queue may then run on sibling threads too
+    pub fn join_work_stealing_group(&mut self, group: Arc<WorkStealingGroup<M>>) {
+        group.add_member(self.message_queue.clone());
+        if let Some(old_group) = self.work_stealing_group.replace(group) {
+            old_group.remove_member(&self.message_queue);
+        }
+    }
+
+    pub fn start(&mut self) {
+        if self.thread.is_some() {
+            return;
+        }
+
+        let message_queue = self.message_queue.clone();
+        let current = self.current.clone();
+        let cpu_affinity = self.thread_config.cpu_affinity.clone();
+        let work_stealing_group = self.work_stealing_group.clone();
+        let worker_status = self.worker_status.clone();
+        worker_status.started(self.thread_name());
+        let mut builder = thread::Builder::new();
+        if let Some(name) = self.thread_name() {
+            builder = builder.name(name.to_string());
+        }
+        if let Some(stack_size) = self.thread_config.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+
+        let thread = builder.spawn(move || {
+            if !cpu_affinity.is_empty() {
+                set_cpu_affinity(&cpu_affinity);
+            }
+            set_current_message_queue(Some(current));
+            match work_stealing_group {
+                Some(group) => {
+                    let mut timeout = group.idle_interval();
+                    loop {
+                        match message_queue.process_next_message_for(Some(timeout), Some(&worker_status)) {
+                            Some(true) => {}
+                            Some(false) => break,
+                            None => {
+                                //look again right away while there is something to steal
+                                let stolen = group.steal_and_run_for(&message_queue, Some(&worker_status));
+                                timeout = if stolen > 0 { Duration::ZERO } else { group.idle_interval() };
+                            }
+                        }
+                    }
+                    group.remove_member(&message_queue);
+                }
+                None => {
+                    while message_queue.process_next_message_for(None, Some(&worker_status)) == Some(true) {
+                    }
+                }
+            }
+            worker_status.stopped();
+            set_current_message_queue(None);
+            println!("MessageThread done");
+        }).unwrap();
+
+        self.thread = Some(thread);
+        println!("MessageThread()  start {}", self.thread.is_none());
+    }
+
+    /**
+     *  Watch fd on this thread: callback runs on the MessageThread whenever fd becomes
+     *  ready for events (FD_EVENT_*), in the same loop that handles posted messages.
+     **/
+    #[cfg(target_os = "linux")]
+    pub fn add_fd_event_source(&self, fd: RawFd, events: u32, callback: FdCallback) -> io::Result<()> {
+        self.message_queue.add_fd_event_source(fd, events, callback)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn remove_fd_event_source(&self, fd: RawFd) -> io::Result<()> {
+        self.message_queue.remove_fd_event_source(fd)
+    }
+
+    pub fn stop(&mut self) {
+        if self.thread.is_none() {
+            return;
+        }
+
+        if let Some(thread) = self.thread.take() {
+            self.message_queue.post_message(None);
+            thread.join().unwrap();
+            println!("MessageThread()  stopped {}", self.thread.is_none());
+        }
+    }
+}
+
+impl<M: Send + 'static> Drop for MessageThread<M> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    enum Command {
+        Add(i64),
+        Report(mpsc::Sender<i64>),
+    }
+
+    struct Accumulator {
+        total: Mutex<i64>,
+    }
+
+    impl MessageHandler<Command> for Accumulator {
+        fn on_message(&self, message_option: Option<Command>) -> bool {
+            match message_option {
+                Some(Command::Add(n)) => *self.total.lock().unwrap() += n,
+                Some(Command::Report(tx)) => tx.send(*self.total.lock().unwrap()).unwrap(),
+                None => return false,
+            }
+            true
+        }
+    }
+
+    #[test]
+    fn enum_messages_by_value() {
+        let message_queue = Arc::new(MessageQueue::<Command>::new());
+        message_queue.set_message_handler(Arc::new(Accumulator { total: Mutex::new(0) }));
+        let mut message_thread = MessageThread::new(message_queue.clone());
+        message_thread.start();
+
+        for i in 1..=100 {
+            message_queue.post_message(Some(Command::Add(i)));
+        }
+        let (tx, rx) = mpsc::channel();
+        message_queue.post_message(Some(Command::Report(tx)));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 5050);
+        message_thread.stop();
+    }
+}
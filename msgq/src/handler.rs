@@ -0,0 +1,102 @@
+use std::sync::Arc;
+use crate::message_queue::*;
+
+
+/**
+ *  Handler
+ *
+ *  Bound to one MessageQueue and one handler_id, like Android's Handler. It posts
+ *  messages and closures to its own handler and knows whether the caller is
+ *  already running on the queue's thread, so cross-thread calls can run inline
+ *  when they are on the right thread and post otherwise.
+ **/
+#[derive(Clone)]
+pub struct Handler {
+    message_queue: Arc<MessageQueue>,
+    handler_id: i32,
+}
+
+impl Handler {
+    pub fn new(message_queue: Arc<MessageQueue>, handler_id: i32) -> Self {
+        Self {
+            message_queue,
+            handler_id,
+        }
+    }
+
+    //Handler for the queue of the calling MessageThread
+    pub fn current(handler_id: i32) -> Option<Self> {
+        MessageQueue::current().map(|message_queue| Self::new(message_queue, handler_id))
+    }
+
+    pub fn handler_id(&self) -> i32 {
+        self.handler_id
+    }
+
+    pub fn message_queue(&self) -> &Arc<MessageQueue> {
+        &self.message_queue
+    }
+
+    pub fn is_current_thread(&self) -> bool {
+        self.message_queue.is_current()
+    }
+
+    pub fn post_message(&self, box_msg: Box<dyn Message + Send>) -> bool {
+        if box_msg.handler_id() != self.handler_id {
+            println!("Handler: message for handler {} posted to handler {}", box_msg.handler_id(), self.handler_id);
+            return false;
+        }
+
+        self.message_queue.post_message(Some(box_msg));
+        true
+    }
+
+    pub fn post<F: FnOnce() + Send + 'static>(&self, runnable: F) {
+        self.message_queue.post_message(Some(Box::new(RunnableMessage::new(self.handler_id, Box::new(runnable)))));
+    }
+
+    pub fn run_or_post<F: FnOnce() + Send + 'static>(&self, runnable: F) {
+        if self.is_current_thread() {
+            runnable();
+        } else {
+            self.post(runnable);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn run_or_post_runs_inline_only_on_the_queue_thread() {
+        let message_queue = Arc::new(MessageQueue::new());
+        let mut message_thread = MessageThread::new(message_queue.clone());
+        message_thread.start();
+
+        let handler = Handler::new(message_queue.clone(), 1);
+        assert!(MessageQueue::current().is_none());
+        assert!(!handler.is_current_thread());
+
+        let (tx, rx) = mpsc::channel();
+        let thread_handler = handler.clone();
+        handler.post(move || {
+            let current = Handler::current(1).unwrap();
+            let inline_tx = tx.clone();
+            let caller = thread::current().id();
+            //already on the right thread: must run before returning
+            current.run_or_post(move || {
+                inline_tx.send(thread::current().id() == caller).unwrap();
+            });
+            tx.send(thread_handler.is_current_thread()).unwrap();
+        });
+
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        message_thread.stop();
+    }
+}
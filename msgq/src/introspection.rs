@@ -0,0 +1,445 @@
+use std::sync::{Mutex, Arc, Weak};
+use std::sync::atomic::{AtomicU8, AtomicI32, AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::path::{Path, PathBuf};
+use std::io;
+use serde::{Serialize, Deserialize};
+#[cfg(target_os = "linux")]
+use std::thread;
+use crate::generic;
+use crate::message_queue::MessageQueue;
+
+
+/**
+ *  PendingMessageInfo
+ *  one entry of a queue, as seen by MessageQueue::snapshot()
+ **/
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PendingMessageInfo {
+    pub handler_id: Option<i32>,
+    pub type_name: String,
+    //since it was posted
+    pub age_ms: u64,
+    pub asynchronous: bool,
+    pub thread_affine: bool,
+    pub cancelled: bool,
+    //set for sync barriers, which are not messages
+    pub sync_barrier: Option<i32>,
+}
+
+/**
+ *  QueueSnapshot
+ **/
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueueSnapshot {
+    pub name: Option<String>,
+    pub expired_count: usize,
+    //in delivery order, barriers included
+    pub pending: Vec<PendingMessageInfo>,
+}
+
+
+/**
+ *  WorkerStatus
+ *  what a MessageThread is doing, updated by the thread itself
+ **/
+const WORKER_NOT_STARTED: u8 = 0;
+const WORKER_IDLE: u8 = 1;
+const WORKER_RUNNING: u8 = 2;
+const WORKER_STOPPED: u8 = 3;
+const NO_HANDLER_ID: i32 = i32::MIN;
+
+pub struct WorkerStatus {
+    thread_name: Mutex<Option<String>>,
+    state: AtomicU8,
+    handler_id: AtomicI32,
+    //micros since base, when the current message started
+    since_us: AtomicU64,
+    dispatched: AtomicU64,
+    base: Instant,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WorkerSnapshot {
+    pub thread_name: Option<String>,
+    //"not_started", "idle", "running" or "stopped"
+    pub state: String,
+    //handler of the message being dispatched
+    pub handler_id: Option<i32>,
+    pub running_ms: Option<u64>,
+    pub dispatched: u64,
+}
+
+impl Default for WorkerStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WorkerStatus {
+    pub fn new() -> Self {
+        Self {
+            thread_name: Mutex::new(None),
+            state: AtomicU8::new(WORKER_NOT_STARTED),
+            handler_id: AtomicI32::new(NO_HANDLER_ID),
+            since_us: AtomicU64::new(0),
+            dispatched: AtomicU64::new(0),
+            base: Instant::now(),
+        }
+    }
+
+    pub(crate) fn started(&self, thread_name: Option<&str>) {
+        *self.thread_name.lock().unwrap() = thread_name.map(String::from);
+        self.state.store(WORKER_IDLE, Ordering::Release);
+    }
+
+    pub(crate) fn begin(&self, handler_id: Option<i32>) {
+        self.handler_id.store(handler_id.unwrap_or(NO_HANDLER_ID), Ordering::Relaxed);
+        self.since_us.store(self.base.elapsed().as_micros() as u64, Ordering::Relaxed);
+        self.state.store(WORKER_RUNNING, Ordering::Release);
+    }
+
+    //messages dispatched since begin(), more than 1 for a batch
+    pub(crate) fn end(&self, dispatched: u64) {
+        self.dispatched.fetch_add(dispatched, Ordering::Relaxed);
+        self.state.store(WORKER_IDLE, Ordering::Release);
+    }
+
+    pub(crate) fn stopped(&self) {
+        self.state.store(WORKER_STOPPED, Ordering::Release);
+    }
+
+    //the thread loop ended, by stop() or a handler returning false
+    pub fn is_stopped(&self) -> bool {
+        self.state.load(Ordering::Acquire) == WORKER_STOPPED
+    }
+
+    pub fn snapshot(&self) -> WorkerSnapshot {
+        let state = self.state.load(Ordering::Acquire);
+        let running = state == WORKER_RUNNING;
+        let handler_id = self.handler_id.load(Ordering::Relaxed);
+        WorkerSnapshot {
+            thread_name: self.thread_name.lock().unwrap().clone(),
+            state: match state {
+                WORKER_NOT_STARTED => "not_started",
+                WORKER_IDLE => "idle",
+                WORKER_RUNNING => "running",
+                _ => "stopped",
+            }.to_string(),
+            handler_id: if running && handler_id != NO_HANDLER_ID { Some(handler_id) } else { None },
+            running_ms: if running {
+                Some((self.base.elapsed().as_micros() as u64).saturating_sub(self.since_us.load(Ordering::Relaxed)) / 1000)
+            } else {
+                None
+            },
+            dispatched: self.dispatched.load(Ordering::Relaxed),
+        }
+    }
+}
+
+
+/**
+ *  QueueIntrospection
+ **/
+pub trait QueueIntrospection {
+    fn snapshot(&self) -> QueueSnapshot;
+}
+
+impl<M: Send + 'static> QueueIntrospection for generic::MessageQueue<M> {
+    fn snapshot(&self) -> QueueSnapshot {
+        generic::MessageQueue::snapshot(self)
+    }
+}
+
+impl QueueIntrospection for MessageQueue {
+    fn snapshot(&self) -> QueueSnapshot {
+        MessageQueue::snapshot(self)
+    }
+}
+
+
+/**
+ *  DebugSnapshot
+ **/
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DebugSnapshot {
+    pub timestamp_ms: u64,
+    pub queues: Vec<QueueSnapshot>,
+    pub workers: Vec<WorkerSnapshot>,
+}
+
+#[derive(Clone, Debug)]
+pub enum DumpTarget {
+    Log,
+    JsonFile(PathBuf),
+}
+
+
+/**
+ *  DebugDump
+ *
+ *  Collects the queues and MessageThreads to look at during an incident. Queues are
+ *  held weakly. snapshot() never takes messages out of a queue.
+ **/
+#[derive(Default)]
+pub struct DebugDump {
+    queues: Mutex<Vec<Weak<dyn QueueIntrospection + Send + Sync>>>,
+    workers: Mutex<Vec<Arc<WorkerStatus>>>,
+}
+
+impl DebugDump {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_queue<Q: QueueIntrospection + Send + Sync + 'static>(&self, queue: &Arc<Q>) {
+        let queue: Arc<dyn QueueIntrospection + Send + Sync> = queue.clone();
+        self.queues.lock().unwrap().push(Arc::downgrade(&queue));
+    }
+
+    //see MessageThread::worker_status()
+    pub fn add_worker(&self, worker_status: Arc<WorkerStatus>) {
+        self.workers.lock().unwrap().push(worker_status);
+    }
+
+    pub fn snapshot(&self) -> DebugSnapshot {
+        let queues: Vec<Arc<dyn QueueIntrospection + Send + Sync>> = {
+            let mut queues = self.queues.lock().unwrap();
+            queues.retain(|queue| queue.strong_count() > 0);
+            queues.iter().filter_map(|queue| queue.upgrade()).collect()
+        };
+
+        DebugSnapshot {
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |dur| dur.as_millis() as u64),
+            queues: queues.iter().map(|queue| queue.snapshot()).collect(),
+            workers: self.workers.lock().unwrap().iter().map(|worker| worker.snapshot()).collect(),
+        }
+    }
+
+    //at info level through libhelper's logger, one record per queue, pending entry and worker
+    pub fn dump_to_log(&self) {
+        let snapshot = self.snapshot();
+        for queue in snapshot.queues.iter() {
+            libhelper::info!(queue = queue.name, pending = queue.pending.len(), expired = queue.expired_count, "DebugDump queue\n");
+            for (index, info) in queue.pending.iter().enumerate() {
+                match info.sync_barrier {
+                    Some(token) => libhelper::info!(queue = queue.name, index = index, sync_barrier = token, "DebugDump pending\n"),
+                    None => libhelper::info!(queue = queue.name, index = index, handler_id = info.handler_id, type_name = info.type_name,
+                        age_ms = info.age_ms, cancelled = info.cancelled, "DebugDump pending\n"),
+                }
+            }
+        }
+        for worker in snapshot.workers.iter() {
+            libhelper::info!(thread_name = worker.thread_name, state = worker.state, handler_id = worker.handler_id,
+                running_ms = worker.running_ms, dispatched = worker.dispatched, "DebugDump worker\n");
+        }
+    }
+
+    //written next to path first, so readers never see half a file
+    pub fn dump_to_file(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(&self.snapshot()).map_err(io::Error::other)?;
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, json)?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    pub fn dump(&self, target: &DumpTarget) {
+        match target {
+            DumpTarget::Log => self.dump_to_log(),
+            DumpTarget::JsonFile(path) => {
+                if let Err(e) = self.dump_to_file(path) {
+                    println!("DebugDump: write {:?} failed {}", path, e);
+                }
+            }
+        }
+    }
+
+    /**
+     *  Dump to target whenever signal (e.g. libc::SIGUSR1) arrives. The signal handler
+     *  only writes a byte to a pipe, the dump itself runs on a "debug-dump" thread.
+     **/
+    #[cfg(target_os = "linux")]
+    pub fn install_signal_handler(self: &Arc<Self>, signal: i32, target: DumpTarget) -> io::Result<()> {
+        if signal <= 0 || signal as usize >= SIGNAL_PIPES.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("bad signal {}", signal)));
+        }
+
+        let mut fds = [0 as libc::c_int; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let old_fd = SIGNAL_PIPES[signal as usize].swap(fds[1], Ordering::SeqCst);
+        if old_fd >= 0 {
+            //ends the thread of the previous installation
+            unsafe { libc::close(old_fd) };
+        }
+
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_dump_signal as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let debug_dump = Arc::downgrade(self);
+        let read_fd = fds[0];
+        thread::Builder::new().name("debug-dump".to_string()).spawn(move || {
+            let mut byte = 0u8;
+            loop {
+                let n = unsafe { libc::read(read_fd, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+                if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                if n <= 0 {
+                    break;
+                }
+                match debug_dump.upgrade() {
+                    Some(debug_dump) => debug_dump.dump(&target),
+                    None => break,
+                }
+            }
+            unsafe { libc::close(read_fd) };
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+static SIGNAL_PIPES: [AtomicI32; 65] = [const { AtomicI32::new(-1) }; 65];
+
+#[cfg(target_os = "linux")]
+extern "C" fn on_dump_signal(signal: libc::c_int) {
+    let fd = SIGNAL_PIPES[signal as usize].load(Ordering::SeqCst);
+    if fd >= 0 {
+        let byte = signal as u8;
+        unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::any::Any;
+    use std::time::Duration;
+    use crate::message_queue::*;
+    use libhelper::logger::{self, Level, LogFilter};
+    use libhelper::sink::{LogFormat, RingBufferSink};
+
+    struct StuckMessage {
+    }
+
+    impl Message for StuckMessage {
+        fn handler_id(&self) -> i32 {
+            3
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn snapshot_does_not_take_messages() {
+        let message_queue = Arc::new(MessageQueue::with_options(Some("stuck"), None));
+        message_queue.post_message(Some(Box::new(StuckMessage {})));
+        let token = message_queue.post_sync_barrier();
+        message_queue.post_message_cancellable(Box::new(StuckMessage {})).cancel();
+
+        let snapshot = message_queue.snapshot();
+        assert_eq!(snapshot.name.as_deref(), Some("stuck"));
+        //the cancelled one was taken out by cancel()
+        assert_eq!(snapshot.pending.len(), 2);
+        assert_eq!(snapshot.pending[0].handler_id, Some(3));
+        assert!(snapshot.pending[0].type_name.ends_with("StuckMessage"));
+        assert_eq!(snapshot.pending[1].sync_barrier, Some(token));
+        assert!(!snapshot.pending[0].cancelled);
+        assert_eq!(message_queue.take_messages().len(), 1);
+
+        let typed_queue = generic::MessageQueue::<u32>::new();
+        typed_queue.post_message(Some(1));
+        assert_eq!(typed_queue.snapshot().pending[0].type_name, "u32");
+    }
+
+    #[test]
+    fn dump_to_log_goes_through_the_logger() {
+        let message_queue = Arc::new(MessageQueue::with_options(Some("logged"), None));
+        message_queue.post_message(Some(Box::new(StuckMessage {})));
+        let token = message_queue.post_sync_barrier();
+        let debug_dump = DebugDump::new();
+        debug_dump.add_queue(&message_queue);
+
+        let ring_buffer = Arc::new(RingBufferSink::new(16).format(LogFormat::JsonLines));
+        let sink_id = logger::add_sink(LogFilter::new(Level::Off).directive("msgq::introspection", Level::Info), ring_buffer.clone());
+        debug_dump.dump(&DumpTarget::Log);
+        logger::flush();
+        logger::remove_sink(sink_id);
+
+        let records: Vec<serde_json::Value> = ring_buffer.lines().iter().map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|record| record["fields"]["queue"] == "logged").collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["level"], "INFO");
+        assert_eq!(records[0]["fields"]["pending"], 2);
+        assert_eq!(records[1]["fields"]["handler_id"], 3);
+        assert!(records[1]["fields"]["type_name"].as_str().unwrap().ends_with("StuckMessage"));
+        assert_eq!(records[2]["fields"]["sync_barrier"], token);
+    }
+
+    struct BlockingHandler {
+        rx: Mutex<std::sync::mpsc::Receiver<()>>,
+    }
+
+    impl MessageHandler for BlockingHandler {
+        fn on_message(&self, _option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+            self.rx.lock().unwrap().recv_timeout(Duration::from_secs(5)).ok();
+            true
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn signal_dumps_queues_and_workers_to_json() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let message_queue = Arc::new(MessageQueue::with_options(Some("dumped"), None));
+        message_queue.register_message_handler(3, Arc::new(BlockingHandler { rx: Mutex::new(rx) }));
+        let mut message_thread = MessageThread::new(message_queue.clone());
+        message_thread.start();
+        for _ in 0..3 {
+            message_queue.post_message(Some(Box::new(StuckMessage {})));
+        }
+
+        let debug_dump = Arc::new(DebugDump::new());
+        debug_dump.add_queue(&message_queue);
+        debug_dump.add_worker(message_thread.worker_status());
+        let path = std::env::temp_dir().join(format!("msgq_debug_dump_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        debug_dump.install_signal_handler(libc::SIGUSR2, DumpTarget::JsonFile(path.clone())).unwrap();
+
+        //wait for the worker to be stuck in the first message
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while message_queue.snapshot().pending.len() != 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        unsafe { libc::raise(libc::SIGUSR2) };
+        while !path.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let snapshot: DebugSnapshot = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(snapshot.queues[0].pending.len(), 2);
+        assert_eq!(snapshot.workers[0].thread_name.as_deref(), Some("dumped"));
+        assert_eq!(snapshot.workers[0].state, "running");
+        assert_eq!(snapshot.workers[0].handler_id, Some(3));
+
+        for _ in 0..3 {
+            tx.send(()).unwrap();
+        }
+        message_thread.stop();
+        assert_eq!(message_thread.worker_status().snapshot().state, "stopped");
+        assert_eq!(message_thread.worker_status().snapshot().dispatched, 3);
+    }
+}
@@ -1,8 +1,29 @@
 pub mod message_queue;
+pub mod generic;
+pub mod message_pool;
+pub mod builder;
+pub mod cancellation;
+pub mod expiry_sweeper;
+pub mod work_stealing;
+pub mod introspection;
+pub mod clock;
+pub mod deterministic;
+pub mod state_machine;
+pub mod saga;
 pub mod test;
+pub mod sharded;
+pub mod handler;
+pub mod ffi;
+mod sync;
+#[cfg(all(test, loom))]
+mod loom_tests;
+#[cfg(target_os = "linux")]
+pub mod shm_message_queue;
+#[cfg(target_os = "linux")]
+pub mod fd_event_source;
 
 #[no_mangle]
-pub extern fn rust_function_b() {
+pub extern "C" fn rust_function_b() {
     println!("Hello this is rust function a\n");
 }
 
@@ -0,0 +1,212 @@
+//post/get/timeout/stop races of MessageQueueVector, MessageQueueHandlers and
+//MessageThread under every interleaving loom finds. A lost wakeup shows up as a
+//deadlock, a lost or duplicated message as a failed assert. Run with
+//  RUSTFLAGS="--cfg loom" cargo test --release --lib loom_tests
+
+use std::sync::Arc;
+use std::any::Any;
+use std::time::Duration;
+use loom::sync::atomic::{AtomicUsize, Ordering};
+use loom::thread;
+use crate::generic::*;
+use crate::clock::VirtualClock;
+use crate::message_queue;
+
+
+fn model<F: Fn() + Sync + Send + 'static>(f: F) {
+    let mut builder = loom::model::Builder::new();
+    if builder.preemption_bound.is_none() {
+        builder.preemption_bound = Some(3);
+    }
+    builder.check(f);
+}
+
+struct CountingHandler {
+    handled: AtomicUsize,
+}
+
+impl MessageHandler<i32> for CountingHandler {
+    fn on_message(&self, message_option: Option<i32>) -> bool {
+        match message_option {
+            Some(_) => {
+                self.handled.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[test]
+fn post_wakes_blocked_get() {
+    model(|| {
+        let message_queue = Arc::new(MessageQueueVector::<i32>::new());
+        let consumer_queue = message_queue.clone();
+        let consumer = thread::spawn(move || consumer_queue.get_message());
+
+        message_queue.post_message(Some(1));
+        assert_eq!(consumer.join().unwrap(), Some(1));
+    });
+}
+
+#[test]
+fn two_consumers_get_each_message_once() {
+    model(|| {
+        let message_queue = Arc::new(MessageQueueVector::<i32>::new());
+        let consumers: Vec<_> = (0..2).map(|_| {
+            let consumer_queue = message_queue.clone();
+            thread::spawn(move || consumer_queue.get_message())
+        }).collect();
+
+        message_queue.post_message(Some(1));
+        message_queue.post_message(Some(2));
+        let mut got: Vec<i32> = consumers.into_iter().map(|consumer| consumer.join().unwrap().unwrap()).collect();
+        got.sort();
+        assert_eq!(got, vec![1, 2]);
+    });
+}
+
+#[test]
+fn try_get_on_empty_queue_does_not_panic() {
+    model(|| {
+        let message_queue = Arc::new(MessageQueueVector::<i32>::new());
+        let other_queue = message_queue.clone();
+        let other = thread::spawn(move || other_queue.try_get_message());
+
+        message_queue.post_message(Some(1));
+        let mine = message_queue.try_get_message();
+        let got = [mine, other.join().unwrap()].iter().filter(|taken| **taken == Some(Some(1))).count();
+        assert!(got <= 1);
+        assert_eq!(got + message_queue.pending_count(), 1);
+    });
+}
+
+#[test]
+fn timed_out_get_does_not_lose_the_message() {
+    model(|| {
+        let clock = Arc::new(VirtualClock::new());
+        let message_queue = Arc::new(MessageQueue::<i32>::with_clock(None, None, clock.clone()));
+        let consumer_queue = message_queue.clone();
+        let consumer = thread::spawn(move || consumer_queue.get_message_timeout(Duration::from_millis(10)));
+        let poster_queue = message_queue.clone();
+        let poster = thread::spawn(move || poster_queue.post_message(Some(1)));
+
+        clock.advance(Duration::from_millis(10));
+        let got = consumer.join().unwrap();
+        poster.join().unwrap();
+        match got {
+            Some(message) => assert_eq!(message, 1),
+            None => assert_eq!(message_queue.try_get_message(), Some(Some(1))),
+        }
+    });
+}
+
+#[test]
+fn bounded_post_waits_for_room() {
+    model(|| {
+        let message_queue = Arc::new(MessageQueueVector::<i32>::with_capacity(Some(1)));
+        let poster_queue = message_queue.clone();
+        let poster = thread::spawn(move || {
+            poster_queue.post_message(Some(1));
+            poster_queue.post_message(Some(2));
+        });
+
+        assert_eq!(message_queue.get_message(), Some(1));
+        assert_eq!(message_queue.get_message(), Some(2));
+        poster.join().unwrap();
+    });
+}
+
+#[test]
+fn interrupt_wakes_blocked_get() {
+    model(|| {
+        let message_queue = Arc::new(MessageQueueVector::<i32>::new());
+        let consumer_queue = message_queue.clone();
+        let consumer = thread::spawn(move || consumer_queue.get_message_interruptible(None).is_none());
+
+        message_queue.interrupt();
+        assert!(consumer.join().unwrap());
+    });
+}
+
+//whoever takes the stop message in get_messages() puts it back for the others
+#[test]
+fn get_messages_leaves_stop_message_for_other_waiters() {
+    model(|| {
+        let message_queue = Arc::new(MessageQueueVector::<i32>::new());
+        let batch_queue = message_queue.clone();
+        let batch = thread::spawn(move || batch_queue.get_messages(4, None).len());
+        let single_queue = message_queue.clone();
+        let single = thread::spawn(move || single_queue.get_message());
+
+        message_queue.post_message(Some(1));
+        message_queue.post_message(None);
+        let batch_count = batch.join().unwrap();
+        let single_count = single.join().unwrap().map_or(0, |_| 1);
+        assert_eq!(batch_count + single_count, 1);
+    });
+}
+
+#[test]
+fn stop_races_with_post() {
+    model(|| {
+        let message_queue = Arc::new(MessageQueue::<i32>::new());
+        let handler = Arc::new(CountingHandler { handled: AtomicUsize::new(0) });
+        message_queue.set_message_handler(handler.clone());
+        let mut message_thread = MessageThread::new(message_queue.clone());
+        message_thread.start();
+
+        let poster_queue = message_queue.clone();
+        let poster = thread::spawn(move || poster_queue.post_message(Some(1)));
+        message_thread.stop();
+        poster.join().unwrap();
+
+        //handled before the stop message, or still waiting for the next start()
+        let handled = handler.handled.load(Ordering::SeqCst);
+        assert_eq!(handled + message_queue.pending_count(), 1);
+    });
+}
+
+struct HelloMessage;
+
+impl message_queue::Message for HelloMessage {
+    fn handler_id(&self) -> i32 {
+        1
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct CountingMessageHandler {
+    handled: AtomicUsize,
+}
+
+impl message_queue::MessageHandler for CountingMessageHandler {
+    fn on_message(&self, _option_box_msg: Option<Box<dyn message_queue::Message + Send>>) -> bool {
+        self.handled.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+}
+
+#[test]
+fn register_races_with_dispatch() {
+    model(|| {
+        let message_queue = Arc::new(message_queue::MessageQueue::new());
+        assert!(!message_queue::MessageQueueHandlers::new().dispatch_message(None));
+        let hello = Arc::new(CountingMessageHandler { handled: AtomicUsize::new(0) });
+        message_queue.register_message_handler(1, hello.clone());
+        let mut message_thread = message_queue::MessageThread::new(message_queue.clone());
+        message_thread.start();
+
+        let register_queue = message_queue.clone();
+        let register = thread::spawn(move || {
+            register_queue.register_message_handler(2, Arc::new(CountingMessageHandler { handled: AtomicUsize::new(0) }));
+        });
+        message_queue.post_message(Some(Box::new(HelloMessage)));
+        register.join().unwrap();
+        message_thread.stop();
+        assert_eq!(hello.handled.load(Ordering::SeqCst), 1);
+    });
+}
@@ -0,0 +1,202 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::message_queue::*;
+
+
+/**
+ *  MessagePoolStats
+ **/
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MessagePoolStats {
+    //obtain() reused a recycled box
+    pub hits: usize,
+    //obtain() had to allocate
+    pub misses: usize,
+    //boxes returned to the pool after on_message_ref()
+    pub recycled: usize,
+    //boxes freed because the pool was already full
+    pub discarded: usize,
+    //boxes handed to on_message() by value, so they could not come back
+    pub escaped: usize,
+    //boxes currently waiting in the pool
+    pub pooled: usize,
+}
+
+
+/**
+ *  RecycleMessage
+ *  type-erased side of MessagePool<T>, used by MessageQueueHandlers at dispatch time
+ **/
+pub trait RecycleMessage {
+    //gives the box back if it is not a T
+    fn recycle_message(&self, box_msg: Box<dyn Message + Send>) -> Option<Box<dyn Message + Send>>;
+    fn note_escaped(&self);
+}
+
+
+/**
+ *  MessagePool
+ *
+ *  Free list of message boxes. MessageQueue::obtain_message() hands them out and the
+ *  queue puts them back once a handler has seen the message via on_message_ref(),
+ *  so a steady stream of one message type stops hitting the allocator.
+ **/
+pub struct MessagePool<T> {
+    free_mutex: Mutex<Vec<Box<T>>>,
+    max_size: usize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    recycled: AtomicUsize,
+    discarded: AtomicUsize,
+    escaped: AtomicUsize,
+}
+
+impl<T: Message + Send + 'static> MessagePool<T> {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            free_mutex: Mutex::new(Vec::with_capacity(max_size)),
+            max_size,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            recycled: AtomicUsize::new(0),
+            discarded: AtomicUsize::new(0),
+            escaped: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn obtain(&self, msg: T) -> Box<T> {
+        let box_option = self.free_mutex.lock().unwrap().pop();
+        match box_option {
+            Some(mut box_msg) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                *box_msg = msg;
+                box_msg
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Box::new(msg)
+            }
+        }
+    }
+
+    pub fn recycle(&self, box_msg: Box<T>) {
+        let mut free = self.free_mutex.lock().unwrap();
+        if free.len() < self.max_size {
+            free.push(box_msg);
+            self.recycled.fetch_add(1, Ordering::Relaxed);
+        } else {
+            drop(free);
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> MessagePoolStats {
+        MessagePoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            recycled: self.recycled.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+            escaped: self.escaped.load(Ordering::Relaxed),
+            pooled: self.free_mutex.lock().unwrap().len(),
+        }
+    }
+}
+
+impl<T: Message + Send + 'static> RecycleMessage for MessagePool<T> {
+    fn recycle_message(&self, box_msg: Box<dyn Message + Send>) -> Option<Box<dyn Message + Send>> {
+        //as_any() must be the box's own T, not something it wraps
+        let any = box_msg.as_any();
+        let same_object = any as *const dyn std::any::Any as *const u8 == box_msg.as_ref() as *const dyn Message as *const u8;
+        if !any.is::<T>() || !same_object {
+            return Some(box_msg);
+        }
+
+        let box_t = unsafe { Box::from_raw(Box::into_raw(box_msg) as *mut T) };
+        self.recycle(box_t);
+        None
+    }
+
+    fn note_escaped(&self) {
+        self.escaped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::any::Any;
+    use std::sync::Arc;
+
+    struct SmallMessage {
+        handler_id: i32,
+        value: u32,
+    }
+
+    impl Message for SmallMessage {
+        fn handler_id(&self) -> i32 {
+            self.handler_id
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct BorrowingHandler {
+        sum: AtomicUsize,
+    }
+
+    impl MessageHandler for BorrowingHandler {
+        fn on_message(&self, _option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+            true
+        }
+
+        fn on_message_ref(&self, msg: &(dyn Message + Send)) -> Option<bool> {
+            let small_msg = msg.as_any().downcast_ref::<SmallMessage>()?;
+            self.sum.fetch_add(small_msg.value as usize, Ordering::SeqCst);
+            Some(true)
+        }
+    }
+
+    struct OwningHandler {
+    }
+
+    impl MessageHandler for OwningHandler {
+        fn on_message(&self, _option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn boxes_come_back_after_dispatch() {
+        let message_queue = MessageQueue::new();
+        let handler = Arc::new(BorrowingHandler { sum: AtomicUsize::new(0) });
+        message_queue.register_message_handler(1, handler.clone());
+        message_queue.register_message_handler(2, Arc::new(OwningHandler {}));
+        let pool = message_queue.enable_message_pool::<SmallMessage>(2);
+
+        for value in 0..10 {
+            message_queue.post_message(Some(message_queue.obtain_message(SmallMessage { handler_id: 1, value })));
+            assert!(message_queue.process_next_message());
+        }
+        assert_eq!(handler.sum.load(Ordering::SeqCst), 45);
+        assert_eq!(pool.stats(), MessagePoolStats { hits: 9, misses: 1, recycled: 10, discarded: 0, escaped: 0, pooled: 1 });
+
+        //two in flight: the pool allocates once more, then keeps both
+        for value in 0..2 {
+            message_queue.post_message(Some(message_queue.obtain_message(SmallMessage { handler_id: 1, value })));
+        }
+        //an owning handler keeps the box
+        message_queue.post_message(Some(message_queue.obtain_message(SmallMessage { handler_id: 2, value: 0 })));
+        for _ in 0..3 {
+            assert!(message_queue.process_next_message());
+        }
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.misses, stats.escaped, stats.pooled), (10, 3, 1, 2));
+    }
+}
@@ -1,8 +1,22 @@
-use std::sync::{Mutex, Arc, Condvar};
-use std::thread;
-use std::time::Duration;
+use std::sync::{Mutex, Arc};
+use std::time::{Duration, Instant};
 use std::collections::HashMap;
-use std::any::Any;
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+#[cfg(target_os = "linux")]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::RawFd;
+#[cfg(target_os = "linux")]
+use crate::fd_event_source::*;
+use crate::generic;
+use crate::work_stealing;
+use crate::introspection::{QueueSnapshot, WorkerStatus};
+use crate::clock::{Clock, SystemClock};
+use crate::message_pool::*;
+use crate::sync;
+pub use crate::generic::{IdleHandler, MessageMeta, ThreadConfig, ExpiryCallback, HandlerLimit, FairnessMode, HandlerWaitStats, MAX_CPUS};
+pub use crate::cancellation::{CancellationToken, is_cancellation_requested};
 
 
 //https://bennetthardwick.com/blog/dont-use-boxed-trait-objects-for-struct-internals/
@@ -12,75 +26,148 @@ use std::any::Any;
 pub trait Message {
     fn handler_id(&self) -> i32;
     fn as_any(&self) -> &dyn Any;
-}
 
-/**
- *  MessageHandler
- **/
-pub trait MessageHandler {
-    fn on_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool;
+    //messages with the same key are kept in order by ShardedDispatcher
+    fn key(&self) -> u64 {
+        self.handler_id() as u64
+    }
+
+    //asynchronous messages are still delivered while a sync barrier is posted
+    fn is_asynchronous(&self) -> bool {
+        false
+    }
+
+    //must run on the thread of the queue it was posted to, see WorkStealingGroup
+    fn is_thread_affine(&self) -> bool {
+        false
+    }
+
+    fn type_name(&self) -> String {
+        libhelper::helper::type_of(self)
+    }
 }
 
 /**
- *  MessageQueueVector
+ *  RunnableMessage
+ *  closure posted by Handler::post(), run on the queue's thread instead of being dispatched
  **/
-pub struct MessageQueueVector {
-    messages_mutex: Mutex<Vec<Option<Box<dyn Message + Send>>>>,
-    cond: Condvar,
+pub struct RunnableMessage {
+    handler_id: i32,
+    runnable: RefCell<Option<Box<dyn FnOnce() + Send>>>,
 }
 
-impl MessageQueueVector {
-    pub fn new() -> Self {
+impl RunnableMessage {
+    pub fn new(handler_id: i32, runnable: Box<dyn FnOnce() + Send>) -> Self {
         Self {
-            messages_mutex: Mutex::new(Vec::new()),
-            cond: Condvar::new(),
+            handler_id,
+            runnable: RefCell::new(Some(runnable)),
         }
     }
 
-    pub fn get_message(&self) -> Option<Box<dyn Message + Send>> {
-        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
-        while (*messages_mutex_guard).len() == 0 {
-            messages_mutex_guard = self.cond.wait(messages_mutex_guard).unwrap();
+    pub fn run(&self) {
+        if let Some(runnable) = self.runnable.borrow_mut().take() {
+            runnable();
         }
-        (*messages_mutex_guard).remove(0)
     }
+}
 
-    pub fn get_message_timeout(&self, dur: Duration) -> Option<Box<dyn Message + Send>> {
-        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
-        let mut message_option: Option<Box<dyn Message + Send>> = None;
-        
-        if (*messages_mutex_guard).len() == 0 {
-            let mut ret = self.cond.wait_timeout(messages_mutex_guard, dur).unwrap();
-            if !ret.1.timed_out() {
-               message_option = (*(ret.0)).remove(0);
-            }
-        } else {
-            message_option = (*messages_mutex_guard).remove(0);
-        }
+impl Message for RunnableMessage {
+    fn handler_id(&self) -> i32 {
+        self.handler_id
+    }
 
-        message_option
+    fn as_any(&self) -> &dyn Any {
+        self
     }
 
-    pub fn post_message(&self, message_option: Option<Box<dyn Message + Send>>) {
-        let mut messages_mutex_guard = self.messages_mutex.lock().unwrap();
-        (*messages_mutex_guard).push(message_option);
-        self.cond.notify_all();
+    //Handler::run_or_post() callers expect the queue's own thread
+    fn is_thread_affine(&self) -> bool {
+        true
     }
 }
 
+/**
+ *  MessageHandler
+ **/
+pub trait MessageHandler {
+    fn on_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool;
+
+    //Borrowing variant used for messages from a MessagePool, so the box can go back
+    //to the pool afterwards. None falls back to on_message() and the box is not reused.
+    fn on_message_ref(&self, _msg: &(dyn Message + Send)) -> Option<bool> {
+        None
+    }
+
+    //above 1, MessageThread passes up to this many pending messages of the same
+    //handler_id to on_messages() at once
+    fn max_batch_size(&self) -> usize {
+        1
+    }
+
+    //one result per message, in order, the same as on_message() would return
+    fn on_messages(&self, box_msgs: Vec<Box<dyn Message + Send>>) -> Vec<bool> {
+        box_msgs.into_iter().map(|box_msg| self.on_message(Some(box_msg))).collect()
+    }
+}
+
+/**
+ *  MessageQueueVector
+ **/
+pub type MessageQueueVector = generic::MessageQueueVector<Box<dyn Message + Send>>;
+
+/**
+ *  WorkStealingGroup
+ **/
+pub type WorkStealingGroup = work_stealing::WorkStealingGroup<Box<dyn Message + Send>>;
+
 
 /**
  *  MessageQueueHandlers
  **/
 pub struct MessageQueueHandlers {
-    handlers_mutex: Mutex<HashMap<i32, Arc<dyn MessageHandler + Send + Sync>>>,
+    handlers_mutex: sync::Mutex<HashMap<i32, Arc<dyn MessageHandler + Send + Sync>>>,
+    //gets the messages no registered handler_id matches
+    default_handler: sync::RwLock<Option<Arc<dyn MessageHandler + Send + Sync>>>,
+    message_pools: sync::RwLock<HashMap<TypeId, Arc<dyn RecycleMessage + Send + Sync>>>,
 }
 
 impl MessageQueueHandlers {
     pub fn new() -> Self {
         Self {
-            handlers_mutex: Mutex::new(HashMap::new()),
+            handlers_mutex: sync::Mutex::new(HashMap::new()),
+            default_handler: sync::RwLock::new(None),
+            message_pools: sync::RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_default_message_handler(&self, handler: Arc<dyn MessageHandler + Send + Sync>) {
+        *self.default_handler.write().unwrap() = Some(handler);
+    }
+
+    pub fn set_message_pool(&self, type_id: TypeId, message_pool: Arc<dyn RecycleMessage + Send + Sync>) {
+        self.message_pools.write().unwrap().insert(type_id, message_pool);
+    }
+
+    fn deliver(&self, handler: &Arc<dyn MessageHandler + Send + Sync>, option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+        let message_pool_option = {
+            let message_pools = self.message_pools.read().unwrap();
+            if message_pools.is_empty() {
+                None
+            } else {
+                option_box_msg.as_ref().and_then(|box_msg| message_pools.get(&box_msg.as_any().type_id()).cloned())
+            }
+        };
+
+        if let (Some(message_pool), Some(box_msg)) = (message_pool_option, option_box_msg.as_ref()) {
+            match handler.on_message_ref(box_msg.as_ref()) {
+                Some(ret) => {
+                    message_pool.recycle_message(option_box_msg.unwrap());
+                    return ret;
+                }
+                None => message_pool.note_escaped(),
+            }
         }
+        handler.on_message(option_box_msg)
     }
 
     pub fn register_message_handler(&self, handler_id: i32, handler: Arc<dyn MessageHandler + Send + Sync>) {
@@ -95,19 +182,72 @@ impl MessageQueueHandlers {
     }
 
     pub fn dispatch_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+        if let Some(runnable_msg) = option_box_msg.as_ref().and_then(|box_msg| box_msg.as_any().downcast_ref::<RunnableMessage>()) {
+            runnable_msg.run();
+            return true;
+        }
+
+        //the None stop message has no handler
+        let handler_id = match option_box_msg.as_ref() {
+            Some(box_msg) => box_msg.handler_id(),
+            None => return false,
+        };
+        match self.handler_for(handler_id) {
+            Some(handler) => self.deliver(&handler, option_box_msg),
+            None => false,
+        }
+    }
+
+    //not held while the handler runs, other threads of the queue dispatch meanwhile
+    fn handler_for(&self, handler_id: i32) -> Option<Arc<dyn MessageHandler + Send + Sync>> {
         let handlers_hash = self.handlers_mutex.lock().unwrap();
-        let handler_id = option_box_msg.as_ref().unwrap().handler_id();
-        if handler_id < 0 {
-            let handlers_key_value_option = handlers_hash.iter().next();
-            if let Some(handler) = handlers_key_value_option {
-                return handler.1.on_message(option_box_msg);
-            }
+        let handler_option = if handler_id < 0 {
+            handlers_hash.values().next().cloned()
         } else {
-            if let Some(handler) = handlers_hash.get(&option_box_msg.as_ref().unwrap().handler_id()) {
-                return handler.on_message(option_box_msg);
+            handlers_hash.get(&handler_id).cloned()
+        };
+        drop(handlers_hash);
+        handler_option.or_else(|| self.default_handler.read().unwrap().clone())
+    }
+
+    pub fn batch_size_for(&self, handler_id: i32) -> usize {
+        self.handler_for(handler_id).map_or(1, |handler| handler.max_batch_size())
+    }
+
+    //box_msgs all have the same handler_id, pooled boxes are not recycled here
+    pub fn dispatch_messages(&self, box_msgs: Vec<Box<dyn Message + Send>>) -> Vec<bool> {
+        let handler = match box_msgs.first().and_then(|box_msg| self.handler_for(box_msg.handler_id())) {
+            Some(handler) => handler,
+            None => return vec![false; box_msgs.len()],
+        };
+        {
+            let message_pools = self.message_pools.read().unwrap();
+            for box_msg in box_msgs.iter() {
+                if let Some(message_pool) = message_pools.get(&box_msg.as_any().type_id()) {
+                    message_pool.note_escaped();
+                }
             }
         }
-        false
+        handler.on_messages(box_msgs)
+    }
+}
+
+impl generic::MessageHandler<Box<dyn Message + Send>> for MessageQueueHandlers {
+    fn on_message(&self, message_option: Option<Box<dyn Message + Send>>) -> bool {
+        self.dispatch_message(message_option)
+    }
+
+    fn batch_size(&self, box_msg: &Box<dyn Message + Send>, _meta: &MessageMeta) -> usize {
+        self.batch_size_for(box_msg.handler_id())
+    }
+
+    fn on_messages(&self, box_msgs: Vec<Box<dyn Message + Send>>) -> Vec<bool> {
+        self.dispatch_messages(box_msgs)
+    }
+
+    //Handler::post() closures run on their own
+    fn is_batchable(&self, box_msg: &Box<dyn Message + Send>, _meta: &MessageMeta) -> bool {
+        !box_msg.as_any().is::<RunnableMessage>()
     }
 }
 
@@ -115,45 +255,263 @@ impl MessageQueueHandlers {
 
 /**
  *  MessageQueue
+ *  generic::MessageQueue carrying Box<dyn Message + Send>, dispatched by handler_id
  **/
 #[derive(Clone)]
 pub struct MessageQueue {
     //why need double arc in parent and here
     //this because the parent arc.clone() need child also support clone()
-    message_queue_vector: Arc<MessageQueueVector>,
+    message_queue: Arc<generic::MessageQueue<Box<dyn Message + Send>>>,
     message_queue_handlers: Arc<MessageQueueHandlers>,
+    //MessagePool<T> by TypeId::of::<T>()
+    message_pools: Arc<Mutex<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>>,
 }
 
 impl MessageQueue {
     pub fn new() -> Self {
+        Self::with_options(None, None)
+    }
+
+    //see MessageQueueBuilder
+    pub fn with_options(name: Option<&str>, capacity: Option<usize>) -> Self {
+        Self::with_clock(name, capacity, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(name: Option<&str>, capacity: Option<usize>, clock: Arc<dyn Clock>) -> Self {
+        let message_queue = Arc::new(generic::MessageQueue::with_clock(name, capacity, clock));
+        let message_queue_handlers = Arc::new(MessageQueueHandlers::new());
+        message_queue.set_message_handler(message_queue_handlers.clone());
         Self {
-            message_queue_vector: Arc::new(MessageQueueVector::new()),
-            message_queue_handlers: Arc::new(MessageQueueHandlers::new()),
+            message_queue,
+            message_queue_handlers,
+            message_pools: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    pub fn name(&self) -> Option<&str> {
+        self.message_queue.name()
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        self.message_queue.clock()
+    }
+
+    //by the queue's clock
+    pub fn now(&self) -> Instant {
+        self.message_queue.now()
+    }
+
+    pub(crate) fn inner(&self) -> &Arc<generic::MessageQueue<Box<dyn Message + Send>>> {
+        &self.message_queue
+    }
+
     pub fn get_message(&self) -> Option<Box<dyn Message + Send>> {
-        self.message_queue_vector.get_message()
+        self.message_queue.get_message()
     }
 
     pub fn get_message_timeout(&self, duration: Duration) -> Option<Box<dyn Message + Send>> {
-        self.message_queue_vector.get_message_timeout(duration)
+        self.message_queue.get_message_timeout(duration)
+    }
+
+    //waits until timeout for the first message, then takes up to max without waiting
+    pub fn get_messages(&self, max: usize, timeout: Option<Duration>) -> Vec<Box<dyn Message + Send>> {
+        self.message_queue.get_messages(max, timeout)
+    }
+
+    fn message_meta(box_msg: &(dyn Message + Send)) -> MessageMeta {
+        MessageMeta {
+            asynchronous: box_msg.is_asynchronous(),
+            handler_id: Some(box_msg.handler_id()),
+            thread_affine: box_msg.is_thread_affine(),
+            ..MessageMeta::default()
+        }
     }
 
     pub fn post_message(&self, message_option: Option<Box<dyn Message + Send>>) {
-        self.message_queue_vector.post_message(message_option);
+        let meta = match message_option.as_ref() {
+            Some(box_msg) => Self::message_meta(box_msg.as_ref()),
+            None => MessageMeta::default(),
+        };
+        self.message_queue.post_message_with_meta(message_option, meta);
+    }
+
+    /**
+     *  Post and get a token to withdraw the message: cancel() takes a pending message
+     *  out of the queue, a running handler sees is_cancellation_requested().
+     **/
+    pub fn post_message_cancellable(&self, box_msg: Box<dyn Message + Send>) -> CancellationToken {
+        let token = CancellationToken::new();
+        self.post_message_with_token(box_msg, &token);
+        token
+    }
+
+    pub fn post_message_with_token(&self, box_msg: Box<dyn Message + Send>, token: &CancellationToken) {
+        let meta = MessageMeta {
+            cancellation_token: Some(token.clone()),
+            ..Self::message_meta(box_msg.as_ref())
+        };
+        self.message_queue.post_message_with_meta(Some(box_msg), meta);
+    }
+
+    //dropped, or given to the expiry callback, if still pending after ttl
+    pub fn post_message_with_ttl(&self, box_msg: Box<dyn Message + Send>, ttl: Duration) {
+        match self.now().checked_add(ttl) {
+            Some(deadline) => self.post_message_with_deadline(box_msg, deadline),
+            //too far out to ever expire
+            None => self.post_message(Some(box_msg)),
+        }
+    }
+
+    pub fn post_message_with_deadline(&self, box_msg: Box<dyn Message + Send>, deadline: Instant) {
+        let meta = MessageMeta {
+            deadline: Some(deadline),
+            ..Self::message_meta(box_msg.as_ref())
+        };
+        self.message_queue.post_message_with_meta(Some(box_msg), meta);
+    }
+
+    //runs on whichever thread finds the message expired: the reader, or an ExpirySweeper
+    pub fn set_expiry_callback(&self, expiry_callback: Option<ExpiryCallback<Box<dyn Message + Send>>>) {
+        self.message_queue.set_expiry_callback(expiry_callback);
+    }
+
+    pub fn expired_count(&self) -> usize {
+        self.message_queue.expired_count()
+    }
+
+    pub fn sweep_expired(&self) -> usize {
+        self.message_queue.sweep_expired()
+    }
+
+    /**
+     *  Token bucket rate limit and/or concurrency cap for one handler_id. Messages over
+     *  the limit wait in the queue, in order, while other handlers keep going.
+     **/
+    pub fn set_handler_limit(&self, handler_id: i32, limit: HandlerLimit) {
+        self.message_queue.set_handler_limit(handler_id, limit);
+    }
+
+    pub fn remove_handler_limit(&self, handler_id: i32) -> bool {
+        self.message_queue.remove_handler_limit(handler_id)
+    }
+
+    /**
+     *  Serve handler_ids in turn instead of in post order, so a chatty handler
+     *  can't starve the others. Messages of one handler_id keep their order.
+     **/
+    pub fn set_fairness(&self, mode: FairnessMode) {
+        self.message_queue.set_fairness(mode);
+    }
+
+    pub fn fairness(&self) -> FairnessMode {
+        self.message_queue.fairness()
+    }
+
+    //messages per turn under FairnessMode::WeightedDeficitRoundRobin
+    pub fn set_handler_weight(&self, handler_id: i32, weight: u32) {
+        self.message_queue.set_handler_weight(handler_id, weight);
+    }
+
+    //queue wait per handler_id, collected once set_fairness() was called
+    pub fn handler_wait_stats(&self) -> Vec<HandlerWaitStats> {
+        self.message_queue.handler_wait_stats()
+    }
+
+    /**
+     *  The queue the calling thread is serving, set by MessageThread for its thread.
+     **/
+    pub fn current() -> Option<Arc<MessageQueue>> {
+        generic::current_message_queue().and_then(|current| current.downcast::<MessageQueue>().ok())
+    }
+
+    //for threads that drive process_next_message() by themselves
+    pub fn set_current(message_queue_option: Option<Arc<MessageQueue>>) {
+        generic::set_current_message_queue(message_queue_option.map(|message_queue| message_queue as Arc<dyn Any + Send + Sync>));
+    }
+
+    pub fn is_current(&self) -> bool {
+        match Self::current() {
+            Some(message_queue) => message_queue.message_queue.same_queue(&self.message_queue),
+            None => false,
+        }
+    }
+
+    /**
+     *  Hold back every ordinary message posted so far or later until the barrier is
+     *  removed, only Message::is_asynchronous() ones get through.
+     **/
+    pub fn post_sync_barrier(&self) -> i32 {
+        self.message_queue.post_sync_barrier()
+    }
+
+    pub fn remove_sync_barrier(&self, token: i32) -> bool {
+        self.message_queue.remove_sync_barrier(token)
+    }
+
+    pub fn add_idle_handler(&self, idle_handler: IdleHandler) -> i32 {
+        self.message_queue.add_idle_handler(idle_handler)
+    }
+
+    pub fn remove_idle_handler(&self, id: i32) -> bool {
+        self.message_queue.remove_idle_handler(id)
+    }
+
+    pub fn take_messages(&self) -> Vec<Option<Box<dyn Message + Send>>> {
+        self.message_queue.take_messages()
+    }
+
+    /**
+     *  What is pending right now, with handler_id, Message::type_name() and age.
+     *  Nothing is taken out of the queue, see also DebugDump.
+     **/
+    pub fn snapshot(&self) -> QueueSnapshot {
+        self.message_queue.snapshot_with(|box_msg| box_msg.type_name())
     }
 
     pub fn register_message_handler(&self, handler_id: i32, handler: Arc<dyn MessageHandler + Send + Sync>) {
         self.message_queue_handlers.register_message_handler(handler_id, handler);
     }
 
-    pub fn process_next_message(&self) -> bool {
-        let message_option = self.get_message();
-        if message_option.is_some()  {
-            return self.message_queue_handlers.dispatch_message(message_option);
+    pub fn set_default_message_handler(&self, handler: Arc<dyn MessageHandler + Send + Sync>) {
+        self.message_queue_handlers.set_default_message_handler(handler);
+    }
+
+    /**
+     *  Keep up to max_size recycled boxes of T. Use obtain_message() instead of
+     *  Box::new(), and the box comes back once a handler took it by on_message_ref().
+     **/
+    pub fn enable_message_pool<T: Message + Send + 'static>(&self, max_size: usize) -> Arc<MessagePool<T>> {
+        let message_pool = Arc::new(MessagePool::<T>::new(max_size));
+        self.message_queue_handlers.set_message_pool(TypeId::of::<T>(), message_pool.clone());
+        self.message_pools.lock().unwrap().insert(TypeId::of::<T>(), message_pool.clone());
+        message_pool
+    }
+
+    pub fn obtain_message<T: Message + Send + 'static>(&self, msg: T) -> Box<T> {
+        let message_pool_option = self.message_pools.lock().unwrap().get(&TypeId::of::<T>()).cloned();
+        match message_pool_option.and_then(|message_pool| message_pool.downcast::<MessagePool<T>>().ok()) {
+            Some(message_pool) => message_pool.obtain(msg),
+            None => Box::new(msg),
         }
-        false
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn add_fd_event_source(&self, fd: RawFd, events: u32, callback: FdCallback) -> io::Result<()> {
+        self.message_queue.add_fd_event_source(fd, events, callback)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn remove_fd_event_source(&self, fd: RawFd) -> io::Result<()> {
+        self.message_queue.remove_fd_event_source(fd)
+    }
+
+    pub fn process_next_message(&self) -> bool {
+        self.message_queue.process_next_message()
+    }
+
+    //one result per message, several when the handler takes batches (MessageHandler::max_batch_size())
+    pub fn process_next_messages(&self) -> Vec<bool> {
+        self.message_queue.process_next_messages()
     }
 }
 
@@ -162,51 +520,512 @@ impl MessageQueue {
  *  MessageThread
  **/
 pub struct MessageThread {
-    message_queue: Arc<MessageQueue>,
-    thread: Option<thread::JoinHandle<()>>, 
+    message_thread: generic::MessageThread<Box<dyn Message + Send>>,
 }
 
 impl MessageThread {
     pub fn new(message_queue: Arc<MessageQueue>) -> Self {
         Self {
-            message_queue,
-            thread: None,
+            message_thread: generic::MessageThread::with_current(message_queue.message_queue.clone(), message_queue),
         }
     }
 
+    pub fn with_config(message_queue: Arc<MessageQueue>, thread_config: ThreadConfig) -> Self {
+        let mut message_thread = Self::new(message_queue);
+        message_thread.message_thread.set_thread_config(thread_config);
+        message_thread
+    }
+
+    pub fn thread_name(&self) -> Option<&str> {
+        self.message_thread.thread_name()
+    }
+
+    pub fn worker_status(&self) -> Arc<WorkerStatus> {
+        self.message_thread.worker_status()
+    }
+
+    pub fn join_work_stealing_group(&mut self, group: Arc<WorkStealingGroup>) {
+        self.message_thread.join_work_stealing_group(group);
+    }
+
     pub fn start(&mut self) {
-        if self.thread.is_some() {
-            return;
+        self.message_thread.start();
+    }
+
+    /**
+     *  Watch fd on this thread: callback runs on the MessageThread whenever fd becomes
+     *  ready for events (FD_EVENT_*), in the same loop that handles posted messages.
+     **/
+    #[cfg(target_os = "linux")]
+    pub fn add_fd_event_source(&self, fd: RawFd, events: u32, callback: FdCallback) -> io::Result<()> {
+        self.message_thread.add_fd_event_source(fd, events, callback)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn remove_fd_event_source(&self, fd: RawFd) -> io::Result<()> {
+        self.message_thread.remove_fd_event_source(fd)
+    }
+
+    pub fn stop(&mut self) {
+        self.message_thread.stop();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct TestMessage {
+        id: i32,
+        asynchronous: bool,
+    }
+
+    impl Message for TestMessage {
+        fn handler_id(&self) -> i32 {
+            1
         }
 
-        let message_queue = self.message_queue.clone();
-        let thread = thread::spawn(move || {
-            while message_queue.process_next_message() {
-                
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn is_asynchronous(&self) -> bool {
+            self.asynchronous
+        }
+    }
+
+    fn post(message_queue: &MessageQueue, id: i32, asynchronous: bool) {
+        message_queue.post_message(Some(Box::new(TestMessage { id, asynchronous })));
+    }
+
+    fn next_id(message_queue: &MessageQueue) -> Option<i32> {
+        message_queue.get_message_timeout(Duration::from_millis(10))
+            .map(|box_msg| box_msg.as_any().downcast_ref::<TestMessage>().unwrap().id)
+    }
+
+    #[test]
+    fn sync_barrier_only_lets_asynchronous_messages_through() {
+        let message_queue = MessageQueue::new();
+        post(&message_queue, 1, false);
+        let token = message_queue.post_sync_barrier();
+        post(&message_queue, 2, false);
+        post(&message_queue, 3, true);
+        post(&message_queue, 4, false);
+
+        assert_eq!(next_id(&message_queue), Some(1));
+        assert_eq!(next_id(&message_queue), Some(3));
+        assert_eq!(next_id(&message_queue), None);
+
+        assert!(message_queue.remove_sync_barrier(token));
+        assert!(!message_queue.remove_sync_barrier(token));
+        assert_eq!(next_id(&message_queue), Some(2));
+        assert_eq!(next_id(&message_queue), Some(4));
+    }
+
+    #[test]
+    fn idle_handlers_run_when_queue_is_empty() {
+        let message_queue = MessageQueue::new();
+        let once = Arc::new(AtomicUsize::new(0));
+        let always = Arc::new(AtomicUsize::new(0));
+
+        let counter = once.clone();
+        message_queue.add_idle_handler(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            false
+        }));
+        let counter = always.clone();
+        let id = message_queue.add_idle_handler(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            true
+        }));
+
+        post(&message_queue, 1, false);
+        assert_eq!(next_id(&message_queue), Some(1));
+        assert_eq!(always.load(Ordering::SeqCst), 0);
+
+        assert_eq!(next_id(&message_queue), None);
+        assert_eq!(next_id(&message_queue), None);
+        assert_eq!(once.load(Ordering::SeqCst), 1);
+        assert_eq!(always.load(Ordering::SeqCst), 2);
+
+        assert!(message_queue.remove_idle_handler(id));
+        assert_eq!(next_id(&message_queue), None);
+        assert_eq!(always.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn expired_messages_go_to_the_expiry_callback() {
+        let message_queue = MessageQueue::new();
+        let expired_ids = Arc::new(Mutex::new(Vec::new()));
+        let callback_ids = expired_ids.clone();
+        message_queue.set_expiry_callback(Some(Arc::new(move |box_msg: Box<dyn Message + Send>| {
+            callback_ids.lock().unwrap().push(box_msg.as_any().downcast_ref::<TestMessage>().unwrap().id);
+        })));
+
+        message_queue.post_message_with_deadline(Box::new(TestMessage { id: 1, asynchronous: false }), Instant::now());
+        message_queue.post_message_with_ttl(Box::new(TestMessage { id: 2, asynchronous: false }), Duration::from_secs(60));
+        message_queue.post_message_with_ttl(Box::new(TestMessage { id: 3, asynchronous: false }), Duration::ZERO);
+        post(&message_queue, 4, false);
+
+        assert_eq!(next_id(&message_queue), Some(2));
+        assert_eq!(*expired_ids.lock().unwrap(), vec![1]);
+        assert_eq!(message_queue.sweep_expired(), 1);
+        assert_eq!(*expired_ids.lock().unwrap(), vec![1, 3]);
+        assert_eq!(next_id(&message_queue), Some(4));
+        assert_eq!(message_queue.expired_count(), 2);
+    }
+
+    struct KeyedMessage {
+        handler_id: i32,
+        id: i32,
+    }
+
+    impl Message for KeyedMessage {
+        fn handler_id(&self) -> i32 {
+            self.handler_id
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn keyed_ids(message_queue: &MessageQueue, count: usize) -> Vec<i32> {
+        (0..count).map(|_| message_queue.get_message_timeout(Duration::from_millis(10)).unwrap())
+            .map(|box_msg| box_msg.as_any().downcast_ref::<KeyedMessage>().unwrap().id)
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_serves_handlers_in_turn() {
+        let message_queue = MessageQueue::new();
+        message_queue.set_fairness(FairnessMode::RoundRobin);
+        for id in 0..4 {
+            message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 1, id })));
+        }
+        for id in 10..12 {
+            message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 2, id })));
+        }
+
+        assert_eq!(keyed_ids(&message_queue, 6), vec![0, 10, 1, 11, 2, 3]);
+        let wait_stats = message_queue.handler_wait_stats();
+        assert_eq!(wait_stats.iter().map(|stats| (stats.handler_id, stats.dispatched)).collect::<Vec<_>>(), vec![(Some(1), 4), (Some(2), 2)]);
+        assert!(wait_stats[0].max_wait >= wait_stats[0].mean_wait());
+    }
+
+    #[test]
+    fn weighted_deficit_round_robin_follows_weights() {
+        let message_queue = MessageQueue::new();
+        message_queue.set_fairness(FairnessMode::WeightedDeficitRoundRobin);
+        message_queue.set_handler_weight(1, 3);
+        for id in 0..6 {
+            message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 1, id })));
+            message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 2, id: 10 + id })));
+        }
+
+        assert_eq!(keyed_ids(&message_queue, 8), vec![0, 1, 2, 10, 3, 4, 5, 11]);
+        //handler 1 ran dry, handler 2 gets the rest
+        assert_eq!(keyed_ids(&message_queue, 4), vec![12, 13, 14, 15]);
+    }
+
+    struct CountingHandler {
+        handled: AtomicUsize,
+    }
+
+    impl MessageHandler for CountingHandler {
+        fn on_message(&self, _option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+            self.handled.fetch_add(1, Ordering::SeqCst);
+            true
+        }
+    }
+
+    #[test]
+    fn fair_mode_stop_waits_for_earlier_messages() {
+        let message_queue = Arc::new(MessageQueue::new());
+        message_queue.set_fairness(FairnessMode::RoundRobin);
+        let handler = Arc::new(CountingHandler { handled: AtomicUsize::new(0) });
+        for handler_id in 1..4 {
+            message_queue.register_message_handler(handler_id, handler.clone());
+            for id in 0..handler_id {
+                message_queue.post_message(Some(Box::new(KeyedMessage { handler_id, id })));
             }
-            print!("MessageThread done\n");
-        });
+        }
 
-        self.thread = Some(thread);
-        print!("MessageThread()  start {}\n", self.thread.is_none());
+        let mut message_thread = MessageThread::new(message_queue.clone());
+        message_thread.start();
+        message_thread.stop();
+        assert_eq!(handler.handled.load(Ordering::SeqCst), 6);
     }
 
-    pub fn stop(&mut self) {
-        if self.thread.is_none() {
-            return;
+    #[test]
+    fn rate_limited_messages_are_deferred() {
+        let message_queue = MessageQueue::new();
+        message_queue.set_handler_limit(1, HandlerLimit { rate_per_second: Some(20.0), burst: 1, max_concurrent: None });
+        for id in 0..3 {
+            message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 1, id })));
+            message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 2, id: 10 + id })));
+        }
+
+        let start = Instant::now();
+        let mut ids = Vec::new();
+        while let Some(box_msg) = message_queue.get_message_timeout(Duration::from_secs(5)) {
+            ids.push(box_msg.as_any().downcast_ref::<KeyedMessage>().unwrap().id);
+            if ids.len() == 6 {
+                break;
+            }
         }
-        
-        if let Some(thread) = self.thread.take() {
-            self.message_queue.post_message(None);
-            thread.join().unwrap();
-            print!("MessageThread()  stopped {}\n", self.thread.is_none());
+        //handler 2 is not held back by handler 1, which gets one message per 50ms
+        assert_eq!(ids, vec![0, 10, 11, 12, 1, 2]);
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn stop_waits_for_rate_limited_messages() {
+        let message_queue = Arc::new(MessageQueue::new());
+        message_queue.set_handler_limit(1, HandlerLimit { rate_per_second: Some(50.0), burst: 1, max_concurrent: None });
+        let handler = Arc::new(CountingHandler { handled: AtomicUsize::new(0) });
+        message_queue.register_message_handler(1, handler.clone());
+        for id in 0..3 {
+            message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 1, id })));
         }
+
+        let mut message_thread = MessageThread::new(message_queue.clone());
+        message_thread.start();
+        message_thread.stop();
+        assert_eq!(handler.handled.load(Ordering::SeqCst), 3);
     }
-}
 
-impl Drop for MessageThread {
-    fn drop(&mut self) {
-        self.stop();
+    struct SlowHandler {
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+        done: AtomicUsize,
+    }
+
+    impl MessageHandler for SlowHandler {
+        fn on_message(&self, _option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(10));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            self.done.fetch_add(1, Ordering::SeqCst);
+            true
+        }
+    }
+
+    #[test]
+    fn concurrency_cap_across_threads() {
+        let message_queue = Arc::new(MessageQueue::new());
+        let capped = Arc::new(SlowHandler { running: AtomicUsize::new(0), max_running: AtomicUsize::new(0), done: AtomicUsize::new(0) });
+        let free = Arc::new(SlowHandler { running: AtomicUsize::new(0), max_running: AtomicUsize::new(0), done: AtomicUsize::new(0) });
+        message_queue.register_message_handler(1, capped.clone());
+        message_queue.register_message_handler(2, free.clone());
+        message_queue.set_handler_limit(1, HandlerLimit { max_concurrent: Some(1), ..HandlerLimit::default() });
+
+        let mut message_threads: Vec<MessageThread> = (0..3).map(|_| MessageThread::new(message_queue.clone())).collect();
+        for message_thread in message_threads.iter_mut() {
+            message_thread.start();
+        }
+        for id in 0..6 {
+            message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 1, id })));
+            message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 2, id })));
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while capped.done.load(Ordering::SeqCst) + free.done.load(Ordering::SeqCst) < 12 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        //one None per thread
+        for _ in 0..3 {
+            message_queue.post_message(None);
+        }
+        drop(message_threads);
+
+        assert_eq!(capped.done.load(Ordering::SeqCst), 6);
+        assert_eq!(free.done.load(Ordering::SeqCst), 6);
+        assert_eq!(capped.max_running.load(Ordering::SeqCst), 1);
+        assert!(free.max_running.load(Ordering::SeqCst) > 1);
+    }
+
+    #[test]
+    fn cancelled_messages_are_not_delivered() {
+        let message_queue = MessageQueue::new();
+        post(&message_queue, 1, false);
+        let token = message_queue.post_message_cancellable(Box::new(TestMessage { id: 2, asynchronous: false }));
+        let group = CancellationToken::new();
+        for id in 3..6 {
+            message_queue.post_message_with_token(Box::new(TestMessage { id, asynchronous: false }), &group.child_token());
+        }
+        post(&message_queue, 6, false);
+
+        token.cancel();
+        group.cancel();
+        assert_eq!(message_queue.snapshot().pending.len(), 2);
+        assert_eq!(next_id(&message_queue), Some(1));
+        assert_eq!(next_id(&message_queue), Some(6));
+        assert_eq!(next_id(&message_queue), None);
+    }
+
+    #[test]
+    fn cancel_makes_room_in_a_full_queue() {
+        let message_queue = Arc::new(MessageQueue::with_options(None, Some(1)));
+        let token = message_queue.post_message_cancellable(Box::new(TestMessage { id: 1, asynchronous: false }));
+
+        let poster_queue = message_queue.clone();
+        let poster = std::thread::spawn(move || post(&poster_queue, 2, false));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!poster.is_finished());
+
+        //nobody reads the queue, cancel() alone lets the poster in
+        token.cancel();
+        poster.join().unwrap();
+        assert_eq!(next_id(&message_queue), Some(2));
+        assert_eq!(next_id(&message_queue), None);
     }
-}
 
+    struct CancelAwareHandler {
+        tx: Mutex<std::sync::mpsc::Sender<bool>>,
+    }
+
+    impl MessageHandler for CancelAwareHandler {
+        fn on_message(&self, _option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+            let tx = self.tx.lock().unwrap().clone();
+            //started: wait for the poster to cancel us
+            tx.send(false).unwrap();
+            let deadline = std::time::Instant::now() + Duration::from_secs(5);
+            while !is_cancellation_requested() && std::time::Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            tx.send(is_cancellation_requested()).unwrap();
+            true
+        }
+    }
+
+    #[test]
+    fn running_handler_sees_cancellation() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let message_queue = Arc::new(MessageQueue::new());
+        message_queue.register_message_handler(1, Arc::new(CancelAwareHandler { tx: Mutex::new(tx) }));
+        let mut message_thread = MessageThread::new(message_queue.clone());
+        message_thread.start();
+
+        let token = message_queue.post_message_cancellable(Box::new(TestMessage { id: 1, asynchronous: false }));
+        assert!(!rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        token.cancel();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        message_thread.stop();
+        assert!(CancellationToken::current().is_none());
+    }
+
+    struct BatchHandler {
+        batches: Mutex<Vec<Vec<i32>>>,
+    }
+
+    impl MessageHandler for BatchHandler {
+        fn on_message(&self, _option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+            true
+        }
+
+        fn max_batch_size(&self) -> usize {
+            4
+        }
+
+        //odd ids fail
+        fn on_messages(&self, box_msgs: Vec<Box<dyn Message + Send>>) -> Vec<bool> {
+            let ids: Vec<i32> = box_msgs.iter().map(|box_msg| box_msg.as_any().downcast_ref::<KeyedMessage>().unwrap().id).collect();
+            let results = ids.iter().map(|id| id % 2 == 0).collect();
+            self.batches.lock().unwrap().push(ids);
+            results
+        }
+    }
+
+    #[test]
+    fn batch_handler_gets_messages_of_its_handler_id() {
+        let message_queue = MessageQueue::new();
+        let batch_handler = Arc::new(BatchHandler { batches: Mutex::new(Vec::new()) });
+        let other = Arc::new(SlowHandler { running: AtomicUsize::new(0), max_running: AtomicUsize::new(0), done: AtomicUsize::new(0) });
+        message_queue.register_message_handler(1, batch_handler.clone());
+        message_queue.register_message_handler(2, other.clone());
+        for id in 0..6 {
+            message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 1, id })));
+            message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 2, id })));
+        }
+
+        assert_eq!(message_queue.process_next_messages(), vec![true, false, true, false]);
+        //handler 2 keeps its own order
+        for _ in 0..4 {
+            assert_eq!(message_queue.process_next_messages(), vec![true]);
+        }
+        assert_eq!(message_queue.process_next_messages(), vec![true, false]);
+        assert_eq!(*batch_handler.batches.lock().unwrap(), vec![vec![0, 1, 2, 3], vec![4, 5]]);
+        assert_eq!(message_queue.snapshot().pending.len(), 2);
+        assert_eq!(other.done.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn failed_batch_message_does_not_stop_the_thread() {
+        let message_queue = Arc::new(MessageQueue::new());
+        let batch_handler = Arc::new(BatchHandler { batches: Mutex::new(Vec::new()) });
+        message_queue.register_message_handler(1, batch_handler.clone());
+        for id in 0..6 {
+            message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 1, id })));
+        }
+
+        let mut message_thread = MessageThread::new(message_queue.clone());
+        let worker_status = message_thread.worker_status();
+        message_thread.start();
+        message_thread.stop();
+        assert_eq!(*batch_handler.batches.lock().unwrap(), vec![vec![0, 1, 2, 3], vec![4, 5]]);
+        assert_eq!(worker_status.snapshot().dispatched, 6);
+    }
+
+    #[test]
+    fn batch_ends_before_a_runnable() {
+        let message_queue = MessageQueue::new();
+        let batch_handler = Arc::new(BatchHandler { batches: Mutex::new(Vec::new()) });
+        message_queue.register_message_handler(1, batch_handler.clone());
+        let ran = Arc::new(AtomicUsize::new(0));
+        let ran_clone = ran.clone();
+        message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 1, id: 0 })));
+        message_queue.post_message(Some(Box::new(RunnableMessage::new(1, Box::new(move || {
+            ran_clone.fetch_add(1, Ordering::SeqCst);
+        })))));
+        message_queue.post_message(Some(Box::new(KeyedMessage { handler_id: 1, id: 2 })));
+
+        assert_eq!(message_queue.process_next_messages(), vec![true]);
+        assert_eq!(message_queue.process_next_messages().len(), 1);
+        assert_eq!(ran.load(Ordering::SeqCst), 1);
+        assert_eq!(message_queue.process_next_messages(), vec![true]);
+        assert_eq!(*batch_handler.batches.lock().unwrap(), vec![vec![0], vec![2]]);
+    }
+
+    #[test]
+    fn duration_max_means_no_deadline() {
+        let message_queue = MessageQueue::new();
+        post(&message_queue, 1, false);
+        post(&message_queue, 2, false);
+        assert!(message_queue.get_message_timeout(Duration::MAX).is_some());
+        assert_eq!(message_queue.get_messages(4, Some(Duration::MAX)).len(), 1);
+        message_queue.post_message_with_ttl(Box::new(TestMessage { id: 3, asynchronous: false }), Duration::MAX);
+        assert_eq!(next_id(&message_queue), Some(3));
+    }
+
+    #[test]
+    fn get_messages_takes_up_to_max() {
+        let message_queue = MessageQueue::new();
+        assert!(message_queue.get_messages(4, Some(Duration::from_millis(10))).is_empty());
+        for id in 0..3 {
+            post(&message_queue, id, false);
+        }
+        message_queue.post_message(None);
+
+        let ids: Vec<i32> = message_queue.get_messages(2, None).iter()
+            .map(|box_msg| box_msg.as_any().downcast_ref::<TestMessage>().unwrap().id).collect();
+        assert_eq!(ids, vec![0, 1]);
+        //stops before the stop message and leaves it queued
+        assert_eq!(message_queue.get_messages(4, None).len(), 1);
+        assert!(message_queue.get_message().is_none());
+        assert!(message_queue.snapshot().pending.is_empty());
+    }
+}
@@ -0,0 +1,748 @@
+use std::sync::{Mutex, RwLock, Arc, OnceLock};
+use std::collections::HashMap;
+use std::any::Any;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use crate::message_queue::*;
+use crate::handler::Handler;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SagaAction {
+    Execute,
+    Compensate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SagaStatus {
+    Running,
+    //a step failed, completed steps are being compensated in reverse order
+    Compensating,
+    Completed,
+    Compensated,
+    //a compensation failed too, the saga stops where it is
+    CompensationFailed,
+}
+
+impl SagaStatus {
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, SagaStatus::Running | SagaStatus::Compensating)
+    }
+}
+
+
+/**
+ *  SagaRecord
+ *  progress of one saga, what a SagaStore persists after every step
+ **/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SagaRecord {
+    pub id: u64,
+    //SagaDefinition name
+    pub saga: String,
+    pub status: SagaStatus,
+    //steps executed and not compensated yet
+    pub completed: usize,
+    //what the last executed step returned, the input of the next one
+    pub data: Value,
+    //why the saga started compensating
+    pub error: Option<String>,
+}
+
+
+/**
+ *  SagaStore
+ **/
+pub trait SagaStore {
+    fn save(&self, record: &SagaRecord) -> io::Result<()>;
+    fn remove(&self, id: u64) -> io::Result<()>;
+    fn load(&self) -> io::Result<Vec<SagaRecord>>;
+}
+
+//keeps records across coordinators of one process, clones share them
+#[derive(Clone, Default)]
+pub struct MemorySagaStore {
+    records: Arc<Mutex<HashMap<u64, SagaRecord>>>,
+}
+
+impl MemorySagaStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SagaStore for MemorySagaStore {
+    fn save(&self, record: &SagaRecord) -> io::Result<()> {
+        self.records.lock().unwrap().insert(record.id, record.clone());
+        Ok(())
+    }
+
+    fn remove(&self, id: u64) -> io::Result<()> {
+        self.records.lock().unwrap().remove(&id);
+        Ok(())
+    }
+
+    fn load(&self) -> io::Result<Vec<SagaRecord>> {
+        Ok(self.records.lock().unwrap().values().cloned().collect())
+    }
+}
+
+//one saga-<id>.json per unfinished saga in dir, replaced atomically on every save
+pub struct JsonFileSagaStore {
+    dir: PathBuf,
+}
+
+impl JsonFileSagaStore {
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        std::fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("saga-{}.json", id))
+    }
+}
+
+impl SagaStore for JsonFileSagaStore {
+    fn save(&self, record: &SagaRecord) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(record).map_err(io::Error::other)?;
+        let path = self.path(record.id);
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, json)?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    fn remove(&self, id: u64) -> io::Result<()> {
+        match std::fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn load(&self) -> io::Result<Vec<SagaRecord>> {
+        let mut records = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let json = std::fs::read_to_string(&path)?;
+            match serde_json::from_str(&json) {
+                Ok(record) => records.push(record),
+                Err(e) => println!("JsonFileSagaStore: skip {:?} {}", path, e),
+            }
+        }
+        Ok(records)
+    }
+}
+
+
+/**
+ *  SagaStep
+ *  action gets the Execute request, compensation (if any) the Compensate request
+ *  once a later step failed
+ **/
+#[derive(Clone)]
+pub struct SagaStep {
+    name: String,
+    action: Handler,
+    compensation: Option<Handler>,
+}
+
+impl SagaStep {
+    pub fn new(name: &str, action: Handler) -> Self {
+        Self {
+            name: name.to_string(),
+            action,
+            compensation: None,
+        }
+    }
+
+    pub fn compensation(mut self, compensation: Handler) -> Self {
+        self.compensation = Some(compensation);
+        self
+    }
+}
+
+/**
+ *  SagaDefinition
+ **/
+#[derive(Clone)]
+pub struct SagaDefinition {
+    name: String,
+    steps: Vec<SagaStep>,
+}
+
+impl SagaDefinition {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn step(mut self, step: SagaStep) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+
+/**
+ *  SagaRequest
+ *  sent to a step's Handler, answer it with reply() from any thread
+ **/
+pub struct SagaRequest {
+    handler_id: i32,
+    pub saga_id: u64,
+    pub step: usize,
+    pub step_name: String,
+    pub action: SagaAction,
+    pub data: Value,
+    reply_to: Handler,
+}
+
+impl SagaRequest {
+    //Execute: Ok carries the data for the next step. Compensate: Ok is ignored
+    pub fn reply(&self, result: Result<Value, String>) {
+        self.reply_to.post_message(Box::new(SagaReply {
+            handler_id: self.reply_to.handler_id(),
+            saga_id: self.saga_id,
+            step: self.step,
+            action: self.action,
+            result,
+        }));
+    }
+}
+
+impl Message for SagaRequest {
+    fn handler_id(&self) -> i32 {
+        self.handler_id
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct SagaReply {
+    handler_id: i32,
+    saga_id: u64,
+    step: usize,
+    action: SagaAction,
+    result: Result<Value, String>,
+}
+
+impl Message for SagaReply {
+    fn handler_id(&self) -> i32 {
+        self.handler_id
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+
+/**
+ *  SagaParticipant
+ *  a step implementation, register it wrapped in a SagaParticipantHandler
+ **/
+pub trait SagaParticipant {
+    fn execute(&self, step_name: &str, data: &Value) -> Result<Value, String>;
+
+    fn compensate(&self, _step_name: &str, _data: &Value) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub struct SagaParticipantHandler<P> {
+    participant: P,
+}
+
+impl<P: SagaParticipant> SagaParticipantHandler<P> {
+    pub fn new(participant: P) -> Self {
+        Self {
+            participant,
+        }
+    }
+}
+
+impl<P: SagaParticipant> MessageHandler for SagaParticipantHandler<P> {
+    fn on_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+        let box_msg = match option_box_msg {
+            Some(box_msg) => box_msg,
+            None => return false,
+        };
+        let request = match box_msg.as_any().downcast_ref::<SagaRequest>() {
+            Some(request) => request,
+            None => {
+                println!("SagaParticipantHandler: not a SagaRequest");
+                return true;
+            }
+        };
+
+        let result = match request.action {
+            SagaAction::Execute => self.participant.execute(&request.step_name, &request.data),
+            SagaAction::Compensate => self.participant.compensate(&request.step_name, &request.data).map(|_| Value::Null),
+        };
+        request.reply(result);
+        true
+    }
+}
+
+
+pub type SagaFinishedCallback = Arc<dyn Fn(&SagaRecord) + Send + Sync>;
+
+/**
+ *  SagaCoordinator
+ *
+ *  Runs sagas as a MessageHandler on a MessageQueue: sends a SagaRequest to each
+ *  step in turn and moves on when its reply arrives. When a step fails, the
+ *  completed steps are compensated in reverse order. The SagaRecord is saved to
+ *  the SagaStore before every request, so after a restart resume() sends the
+ *  request that was in flight again. Steps should therefore be idempotent. If
+ *  saving fails the saga stops there, resume() picks it up again from the store.
+ **/
+pub struct SagaCoordinator {
+    store: Arc<dyn SagaStore + Send + Sync>,
+    definitions: RwLock<HashMap<String, SagaDefinition>>,
+    //sagas started or resumed here and not finished yet
+    records: Mutex<HashMap<u64, SagaRecord>>,
+    next_id: Mutex<u64>,
+    finished_callback: RwLock<Option<SagaFinishedCallback>>,
+    handler: OnceLock<Handler>,
+}
+
+impl SagaCoordinator {
+    //ids go on after the ones in store, so begin() never reuses a saved saga's id
+    pub fn new(store: Arc<dyn SagaStore + Send + Sync>) -> Self {
+        let next_id = match store.load() {
+            Ok(records) => records.iter().map(|record| record.id + 1).max().unwrap_or(1),
+            Err(e) => {
+                println!("SagaCoordinator: load saga store failed {}", e);
+                1
+            }
+        };
+        Self {
+            store,
+            definitions: RwLock::new(HashMap::new()),
+            records: Mutex::new(HashMap::new()),
+            next_id: Mutex::new(next_id),
+            finished_callback: RwLock::new(None),
+            handler: OnceLock::new(),
+        }
+    }
+
+    pub fn add_definition(&self, definition: SagaDefinition) {
+        self.definitions.write().unwrap().insert(definition.name.clone(), definition);
+    }
+
+    //runs on the coordinator's MessageThread
+    pub fn set_finished_callback(&self, finished_callback: Option<SagaFinishedCallback>) {
+        *self.finished_callback.write().unwrap() = finished_callback;
+    }
+
+    //None once the saga finished, the finished callback gets its last record
+    pub fn record(&self, id: u64) -> Option<SagaRecord> {
+        self.records.lock().unwrap().get(&id).cloned()
+    }
+
+    /**
+     *  Register as handler_id on message_queue, the replies of the steps come back there.
+     **/
+    pub fn start(self: &Arc<Self>, message_queue: Arc<MessageQueue>, handler_id: i32) {
+        if self.handler.set(Handler::new(message_queue.clone(), handler_id)).is_err() {
+            println!("SagaCoordinator already started");
+            return;
+        }
+        message_queue.register_message_handler(handler_id, self.clone());
+    }
+
+    /**
+     *  Start a saga of the named definition with data as input of the first step.
+     **/
+    pub fn begin(&self, saga: &str, data: Value) -> io::Result<u64> {
+        if !self.definitions.read().unwrap().contains_key(saga) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown saga {}", saga)));
+        }
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id - 1
+        };
+        let record = SagaRecord {
+            id,
+            saga: saga.to_string(),
+            status: SagaStatus::Running,
+            completed: 0,
+            data,
+            error: None,
+        };
+        self.store.save(&record)?;
+        self.records.lock().unwrap().insert(id, record.clone());
+        self.advance(record);
+        Ok(id)
+    }
+
+    /**
+     *  Pick up the unfinished sagas of the store, e.g. after a restart. Their
+     *  definitions have to be added first. Returns how many were resumed.
+     **/
+    pub fn resume(&self) -> io::Result<usize> {
+        let mut resumed = 0;
+        for record in self.store.load()? {
+            {
+                let mut next_id = self.next_id.lock().unwrap();
+                *next_id = (*next_id).max(record.id + 1);
+            }
+            if record.status.is_finished() || self.records.lock().unwrap().contains_key(&record.id) {
+                continue;
+            }
+            if !self.definitions.read().unwrap().contains_key(&record.saga) {
+                println!("SagaCoordinator: saga {} has unknown definition {}", record.id, record.saga);
+                continue;
+            }
+            self.records.lock().unwrap().insert(record.id, record.clone());
+            self.advance(record);
+            resumed += 1;
+        }
+        Ok(resumed)
+    }
+
+    //send the request record is waiting for, or finish it
+    fn advance(&self, mut record: SagaRecord) {
+        let definition = match self.definitions.read().unwrap().get(&record.saga) {
+            Some(definition) => definition.clone(),
+            None => return,
+        };
+
+        loop {
+            let (step, action, target) = match record.status {
+                SagaStatus::Running if record.completed < definition.steps.len() => {
+                    let step = &definition.steps[record.completed];
+                    (record.completed, SagaAction::Execute, Some(step.action.clone()))
+                }
+                SagaStatus::Running => {
+                    record.status = SagaStatus::Completed;
+                    break;
+                }
+                SagaStatus::Compensating if record.completed > 0 => {
+                    let step = &definition.steps[record.completed - 1];
+                    (record.completed - 1, SagaAction::Compensate, step.compensation.clone())
+                }
+                SagaStatus::Compensating => {
+                    record.status = SagaStatus::Compensated;
+                    break;
+                }
+                _ => break,
+            };
+
+            //nothing to undo for this step
+            let target = match target {
+                Some(target) => target,
+                None => {
+                    record.completed -= 1;
+                    if !self.save(&record) {
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+            let reply_to = match self.handler.get() {
+                Some(handler) => handler.clone(),
+                None => {
+                    println!("SagaCoordinator: not started, saga {} waits", record.id);
+                    return;
+                }
+            };
+            target.post_message(Box::new(SagaRequest {
+                handler_id: target.handler_id(),
+                saga_id: record.id,
+                step,
+                step_name: definition.steps[step].name.clone(),
+                action,
+                data: record.data.clone(),
+                reply_to,
+            }));
+            return;
+        }
+        self.finish(record);
+    }
+
+    //false if the store failed, the saga is then dropped here until resume() loads it again
+    fn save(&self, record: &SagaRecord) -> bool {
+        if let Err(e) = self.store.save(record) {
+            println!("SagaCoordinator: save saga {} failed {}, stopped until resume()", record.id, e);
+            self.records.lock().unwrap().remove(&record.id);
+            return false;
+        }
+        self.records.lock().unwrap().insert(record.id, record.clone());
+        true
+    }
+
+    //a saga whose compensation failed stays in the store, someone has to clean up after it
+    fn finish(&self, record: SagaRecord) {
+        println!("SagaCoordinator: saga {} {} {:?}", record.id, record.saga, record.status);
+        let stored = if record.status == SagaStatus::CompensationFailed {
+            self.store.save(&record)
+        } else {
+            self.store.remove(record.id)
+        };
+        if let Err(e) = stored {
+            println!("SagaCoordinator: store finished saga {} failed {}", record.id, e);
+        }
+        let finished_callback = self.finished_callback.read().unwrap().clone();
+        if let Some(finished_callback) = finished_callback {
+            finished_callback(&record);
+        }
+        self.records.lock().unwrap().remove(&record.id);
+    }
+
+    fn on_reply(&self, reply: &SagaReply) {
+        let mut record = match self.records.lock().unwrap().get(&reply.saga_id) {
+            Some(record) => record.clone(),
+            None => return,
+        };
+        //a reply to a request sent again by resume(), or for a finished saga
+        let expected = match record.status {
+            SagaStatus::Running => Some((record.completed, SagaAction::Execute)),
+            SagaStatus::Compensating if record.completed > 0 => Some((record.completed - 1, SagaAction::Compensate)),
+            _ => None,
+        };
+        if expected != Some((reply.step, reply.action)) {
+            return;
+        }
+
+        match (reply.action, &reply.result) {
+            (SagaAction::Execute, Ok(data)) => {
+                record.completed += 1;
+                record.data = data.clone();
+            }
+            (SagaAction::Execute, Err(e)) => {
+                record.status = SagaStatus::Compensating;
+                record.error = Some(e.clone());
+            }
+            (SagaAction::Compensate, Ok(_)) => record.completed -= 1,
+            (SagaAction::Compensate, Err(e)) => {
+                println!("SagaCoordinator: compensating step {} of saga {} failed {}", reply.step, record.id, e);
+                record.status = SagaStatus::CompensationFailed;
+            }
+        }
+        if record.status.is_finished() {
+            self.finish(record);
+            return;
+        }
+        if self.save(&record) {
+            self.advance(record);
+        }
+    }
+}
+
+impl MessageHandler for SagaCoordinator {
+    fn on_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+        let box_msg = match option_box_msg {
+            Some(box_msg) => box_msg,
+            None => return false,
+        };
+        if let Some(reply) = box_msg.as_any().downcast_ref::<SagaReply>() {
+            self.on_reply(reply);
+        }
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use serde_json::json;
+
+    const COORDINATOR: i32 = 1;
+    const INVENTORY: i32 = 2;
+    const PAYMENT: i32 = 3;
+    const SHIPPING: i32 = 4;
+
+    //adds its name to data["done"], fails if data["fail"] names it
+    struct Participant {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl SagaParticipant for Participant {
+        fn execute(&self, step_name: &str, data: &Value) -> Result<Value, String> {
+            self.calls.lock().unwrap().push(format!("execute {}", step_name));
+            if data["fail"] == step_name {
+                return Err(format!("{} failed", step_name));
+            }
+            let mut data = data.clone();
+            data["done"].as_array_mut().unwrap().push(json!(step_name));
+            Ok(data)
+        }
+
+        fn compensate(&self, step_name: &str, data: &Value) -> Result<(), String> {
+            self.calls.lock().unwrap().push(format!("compensate {}", step_name));
+            if data["fail_compensation"] == step_name {
+                return Err(format!("{} compensation failed", step_name));
+            }
+            Ok(())
+        }
+    }
+
+    //a MemorySagaStore whose save() fails while fail is set
+    struct FlakyStore {
+        store: MemorySagaStore,
+        fail: AtomicBool,
+    }
+
+    impl SagaStore for FlakyStore {
+        fn save(&self, record: &SagaRecord) -> io::Result<()> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(io::Error::other("disk full"));
+            }
+            self.store.save(record)
+        }
+
+        fn remove(&self, id: u64) -> io::Result<()> {
+            self.store.remove(id)
+        }
+
+        fn load(&self) -> io::Result<Vec<SagaRecord>> {
+            self.store.load()
+        }
+    }
+
+    fn order_saga(message_queue: &Arc<MessageQueue>, calls: &Arc<Mutex<Vec<String>>>) -> SagaDefinition {
+        for handler_id in [INVENTORY, PAYMENT, SHIPPING] {
+            message_queue.register_message_handler(handler_id, Arc::new(SagaParticipantHandler::new(Participant { calls: calls.clone() })));
+        }
+        let handler = |handler_id| Handler::new(message_queue.clone(), handler_id);
+        SagaDefinition::new("order")
+            .step(SagaStep::new("reserve", handler(INVENTORY)).compensation(handler(INVENTORY)))
+            .step(SagaStep::new("charge", handler(PAYMENT)).compensation(handler(PAYMENT)))
+            .step(SagaStep::new("ship", handler(SHIPPING)))
+    }
+
+    fn coordinator(message_queue: &Arc<MessageQueue>, store: Arc<dyn SagaStore + Send + Sync>, definition: SagaDefinition) -> (Arc<SagaCoordinator>, mpsc::Receiver<SagaRecord>) {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let coordinator = Arc::new(SagaCoordinator::new(store));
+        coordinator.add_definition(definition);
+        coordinator.set_finished_callback(Some(Arc::new(move |record: &SagaRecord| tx.lock().unwrap().send(record.clone()).unwrap())));
+        coordinator.start(message_queue.clone(), COORDINATOR);
+        (coordinator, rx)
+    }
+
+    #[test]
+    fn failed_step_compensates_completed_steps_in_reverse() {
+        let message_queue = Arc::new(MessageQueue::new());
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let store = MemorySagaStore::new();
+        let (coordinator, rx) = coordinator(&message_queue, Arc::new(store.clone()), order_saga(&message_queue, &calls));
+        let mut message_thread = MessageThread::new(message_queue.clone());
+        message_thread.start();
+
+        let ok_id = coordinator.begin("order", json!({ "done": [] })).unwrap();
+        let record = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(record.id, ok_id);
+        assert_eq!(record.status, SagaStatus::Completed);
+        assert_eq!(record.data["done"], json!(["reserve", "charge", "ship"]));
+
+        calls.lock().unwrap().clear();
+        let failed_id = coordinator.begin("order", json!({ "done": [], "fail": "ship" })).unwrap();
+        let record = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(record.id, failed_id);
+        assert_eq!(record.status, SagaStatus::Compensated);
+        assert_eq!(record.error.as_deref(), Some("ship failed"));
+        assert_eq!(*calls.lock().unwrap(), vec!["execute reserve", "execute charge", "execute ship", "compensate charge", "compensate reserve"]);
+
+        //only unfinished sagas stay in the store
+        assert!(store.load().unwrap().is_empty());
+
+        let stuck_id = coordinator.begin("order", json!({ "done": [], "fail": "ship", "fail_compensation": "reserve" })).unwrap();
+        let record = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(record.status, SagaStatus::CompensationFailed);
+        //and the ones left for someone to clean up
+        assert_eq!(store.load().unwrap(), vec![record]);
+        assert!(coordinator.begin("refund", json!({})).is_err());
+        message_thread.stop();
+        //finished sagas are not kept in memory
+        for id in [ok_id, failed_id, stuck_id] {
+            assert_eq!(coordinator.record(id), None);
+        }
+    }
+
+    #[test]
+    fn resume_continues_after_restart() {
+        let dir = std::env::temp_dir().join(format!("msgq-saga-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = Arc::new(JsonFileSagaStore::new(&dir).unwrap());
+        //what an earlier process left behind: one saga halfway, one halfway through compensating
+        store.save(&SagaRecord { id: 7, saga: "order".to_string(), status: SagaStatus::Running, completed: 1,
+            data: json!({ "done": ["reserve"] }), error: None }).unwrap();
+        store.save(&SagaRecord { id: 9, saga: "order".to_string(), status: SagaStatus::Compensating, completed: 1,
+            data: json!({ "done": ["reserve"] }), error: Some("charge failed".to_string()) }).unwrap();
+
+        let message_queue = Arc::new(MessageQueue::new());
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let (coordinator, rx) = coordinator(&message_queue, store.clone(), order_saga(&message_queue, &calls));
+        //a saga begun before resume() does not take a saved id
+        assert_eq!(coordinator.begin("order", json!({ "done": [] })).unwrap(), 10);
+        assert_eq!(coordinator.resume().unwrap(), 2);
+        let mut message_thread = MessageThread::new(message_queue.clone());
+        message_thread.start();
+
+        let mut records = [rx.recv_timeout(Duration::from_secs(5)).unwrap(), rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            rx.recv_timeout(Duration::from_secs(5)).unwrap()];
+        records.sort_by_key(|record| record.id);
+        assert_eq!(records[0].status, SagaStatus::Completed);
+        assert_eq!(records[0].data["done"], json!(["reserve", "charge", "ship"]));
+        assert_eq!(records[1].status, SagaStatus::Compensated);
+        assert_eq!(records[2].status, SagaStatus::Completed);
+        assert!(calls.lock().unwrap().contains(&"compensate reserve".to_string()));
+        assert_eq!(calls.lock().unwrap().iter().filter(|call| *call == "execute reserve").count(), 1);
+        assert!(store.load().unwrap().is_empty());
+
+        //ids go on after the resumed ones
+        assert_eq!(coordinator.begin("order", json!({ "done": [] })).unwrap(), 11);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        message_thread.stop();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_save_stops_the_saga_until_resume() {
+        let message_queue = Arc::new(MessageQueue::new());
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let store = Arc::new(FlakyStore { store: MemorySagaStore::new(), fail: AtomicBool::new(false) });
+        let (coordinator, rx) = coordinator(&message_queue, store.clone(), order_saga(&message_queue, &calls));
+
+        let id = coordinator.begin("order", json!({ "done": [] })).unwrap();
+        store.fail.store(true, Ordering::SeqCst);
+        //reserve, then its reply can't be saved so charge is never sent
+        assert!(message_queue.process_next_message());
+        assert!(message_queue.process_next_message());
+        assert!(message_queue.get_message_timeout(Duration::from_millis(10)).is_none());
+        assert_eq!(coordinator.record(id), None);
+        assert_eq!(store.load().unwrap()[0].completed, 0);
+
+        store.fail.store(false, Ordering::SeqCst);
+        assert_eq!(coordinator.resume().unwrap(), 1);
+        while rx.try_recv().is_err() {
+            assert!(message_queue.process_next_message());
+        }
+        assert_eq!(*calls.lock().unwrap(), vec!["execute reserve", "execute reserve", "execute charge", "execute ship"]);
+        assert!(store.load().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,274 @@
+use std::sync::{Mutex, RwLock, Arc};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use crate::message_queue::*;
+use crate::introspection::WorkerStatus;
+
+
+/**
+ *  Shard
+ **/
+struct Shard {
+    message_queue: Arc<MessageQueue>,
+    message_thread: MessageThread,
+    worker_status: Arc<WorkerStatus>,
+}
+
+impl Shard {
+    fn new(handlers: &[(i32, Arc<dyn MessageHandler + Send + Sync>)]) -> Self {
+        let message_queue = Arc::new(MessageQueue::new());
+        for (handler_id, handler) in handlers {
+            message_queue.register_message_handler(*handler_id, handler.clone());
+        }
+
+        let mut message_thread = MessageThread::new(message_queue.clone());
+        let worker_status = message_thread.worker_status();
+        message_thread.start();
+
+        Self {
+            message_queue,
+            message_thread,
+            worker_status,
+        }
+    }
+
+    //its thread ended, e.g. a handler returned false
+    fn is_dead(&self) -> bool {
+        self.worker_status.is_stopped()
+    }
+}
+
+
+/**
+ *  ShardedDispatcher
+ *
+ *  Every message is routed by the hash of Message::key() to one of N MessageQueue,
+ *  each drained by its own MessageThread. Messages with the same key always land on
+ *  the same shard, so they keep FIFO order while different keys run in parallel.
+ *
+ *  A shard whose thread ended because a handler returned false is taken out of the
+ *  routing as soon as a post notices it, its pending messages move to the others.
+ **/
+pub struct ShardedDispatcher {
+    //empty once stopped
+    shards: RwLock<Vec<Shard>>,
+    handlers: Mutex<Vec<(i32, Arc<dyn MessageHandler + Send + Sync>)>>,
+}
+
+impl ShardedDispatcher {
+    pub fn new(shard_count: usize) -> Self {
+        let shard_count = shard_count.max(1);
+        Self {
+            shards: RwLock::new((0..shard_count).map(|_| Shard::new(&[])).collect()),
+            handlers: Mutex::new(Vec::new()),
+        }
+    }
+
+    //0 once stopped
+    pub fn shard_count(&self) -> usize {
+        self.shards.read().unwrap().len()
+    }
+
+    fn shard_index(key: u64, shard_count: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % shard_count as u64) as usize
+    }
+
+    pub fn register_message_handler(&self, handler_id: i32, handler: Arc<dyn MessageHandler + Send + Sync>) {
+        //hold the shards lock so a concurrent rebalance can't miss the new handler
+        let shards = self.shards.read().unwrap();
+        let mut handlers = self.handlers.lock().unwrap();
+        if handlers.iter().any(|(id, _)| *id == handler_id) {
+            println!("handler {} already exist", handler_id);
+            return;
+        }
+
+        for shard in shards.iter() {
+            shard.message_queue.register_message_handler(handler_id, handler.clone());
+        }
+        handlers.push((handler_id, handler));
+    }
+
+    //false once stopped or when no shard is left to take the message
+    pub fn post_message(&self, box_msg: Box<dyn Message + Send>) -> bool {
+        {
+            let shards = self.shards.read().unwrap();
+            if shards.is_empty() {
+                return false;
+            }
+            let shard = &shards[Self::shard_index(box_msg.key(), shards.len())];
+            if !shard.is_dead() {
+                shard.message_queue.post_message(Some(box_msg));
+                if !shard.is_dead() {
+                    return true;
+                }
+                //died meanwhile, remove_dead_shards() moves the message on
+                drop(shards);
+                return self.remove_dead_shards() > 0;
+            }
+        }
+
+        if self.remove_dead_shards() == 0 {
+            return false;
+        }
+        //the new shards were just started
+        self.post_message(box_msg)
+    }
+
+    //returns the shards left
+    fn remove_dead_shards(&self) -> usize {
+        let mut shards = self.shards.write().unwrap();
+        let live = shards.iter().filter(|shard| !shard.is_dead()).count();
+        if live != shards.len() {
+            self.rebuild(&mut shards, live);
+        }
+        shards.len()
+    }
+
+    /**
+     *  Change the number of shards, nothing happens once stopped.
+     *
+     *  Posting is blocked while the old shards are drained, so every message posted
+     *  before the call is handled before any message posted after it for the same key.
+     **/
+    pub fn set_shard_count(&self, shard_count: usize) {
+        let shard_count = shard_count.max(1);
+        let mut shards = self.shards.write().unwrap();
+        if shards.is_empty() || shards.len() == shard_count {
+            return;
+        }
+        self.rebuild(&mut shards, shard_count);
+    }
+
+    fn rebuild(&self, shards: &mut Vec<Shard>, shard_count: usize) {
+        //stop() lets each thread finish what is already queued. Anything left behind
+        //(e.g. a handler returned false and ended its thread) is re-posted in order.
+        let mut leftovers = Vec::new();
+        for mut shard in shards.drain(..) {
+            shard.message_thread.stop();
+            leftovers.extend(shard.message_queue.take_messages().into_iter().flatten());
+        }
+        if shard_count == 0 {
+            if !leftovers.is_empty() {
+                println!("ShardedDispatcher: no shard left, dropped {} messages", leftovers.len());
+            }
+            return;
+        }
+
+        let handlers = self.handlers.lock().unwrap();
+        *shards = (0..shard_count).map(|_| Shard::new(&handlers)).collect();
+        for box_msg in leftovers {
+            let index = Self::shard_index(box_msg.key(), shard_count);
+            shards[index].message_queue.post_message(Some(box_msg));
+        }
+    }
+
+    //handles what is queued, later posts return false
+    pub fn stop(&self) {
+        //what is stuck on a dead shard still gets handled by the others
+        self.remove_dead_shards();
+        let mut shards = self.shards.write().unwrap();
+        self.rebuild(&mut shards, 0);
+    }
+}
+
+impl Drop for ShardedDispatcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::any::Any;
+    use std::collections::HashMap;
+
+    struct KeyedMessage {
+        key: u64,
+        seq: u32,
+    }
+
+    impl Message for KeyedMessage {
+        fn handler_id(&self) -> i32 {
+            1
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn key(&self) -> u64 {
+            self.key
+        }
+    }
+
+    //ends the thread of its shard
+    const POISON: u32 = u32::MAX;
+
+    struct RecordHandler {
+        seen: Mutex<HashMap<u64, Vec<u32>>>,
+    }
+
+    impl MessageHandler for RecordHandler {
+        fn on_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+            if let Some(box_msg) = option_box_msg {
+                let msg = box_msg.as_any().downcast_ref::<KeyedMessage>().unwrap();
+                if msg.seq == POISON {
+                    return false;
+                }
+                self.seen.lock().unwrap().entry(msg.key).or_default().push(msg.seq);
+                return true;
+            }
+            false
+        }
+    }
+
+    #[test]
+    fn same_key_keeps_order_across_rebalance() {
+        let handler = Arc::new(RecordHandler { seen: Mutex::new(HashMap::new()) });
+        let dispatcher = ShardedDispatcher::new(4);
+        dispatcher.register_message_handler(1, handler.clone());
+
+        for seq in 0..300 {
+            if seq == 100 {
+                dispatcher.set_shard_count(2);
+            } else if seq == 200 {
+                dispatcher.set_shard_count(7);
+            }
+            dispatcher.post_message(Box::new(KeyedMessage { key: (seq % 5) as u64, seq }));
+        }
+        dispatcher.stop();
+
+        let seen = handler.seen.lock().unwrap();
+        assert_eq!(seen.len(), 5);
+        for seqs in seen.values() {
+            assert_eq!(seqs.len(), 60);
+            assert!(seqs.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn dead_shard_leaves_the_routing() {
+        let handler = Arc::new(RecordHandler { seen: Mutex::new(HashMap::new()) });
+        let dispatcher = ShardedDispatcher::new(2);
+        dispatcher.register_message_handler(1, handler.clone());
+
+        assert!(dispatcher.post_message(Box::new(KeyedMessage { key: 0, seq: POISON })));
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        for seq in 0..20 {
+            assert!(dispatcher.post_message(Box::new(KeyedMessage { key: (seq % 5) as u64, seq })));
+        }
+        assert_eq!(dispatcher.shard_count(), 1);
+        dispatcher.stop();
+
+        let seen = handler.seen.lock().unwrap();
+        assert_eq!(seen.values().map(|seqs| seqs.len()).sum::<usize>(), 20);
+        assert_eq!(dispatcher.shard_count(), 0);
+        assert!(!dispatcher.post_message(Box::new(KeyedMessage { key: 0, seq: 0 })));
+        dispatcher.set_shard_count(3);
+        assert_eq!(dispatcher.shard_count(), 0);
+    }
+}
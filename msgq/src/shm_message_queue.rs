@@ -0,0 +1,570 @@
+use std::ffi::CString;
+use std::io;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::any::Any;
+use crate::message_queue::*;
+
+
+const SHM_READY_MAGIC: u64 = 0x6d73_6771_5f73_686d; //"msgq_shm"
+const SHM_FLAG_STOP: u32 = 1;
+
+fn round_up8(n: usize) -> usize {
+    (n + 7) & !7
+}
+
+fn shm_name(name: &str) -> io::Result<CString> {
+    let name = if name.starts_with('/') { name.to_string() } else { format!("/{}", name) };
+    CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+
+/**
+ *  ShmMessage
+ *
+ *  Messages cross the process boundary as plain bytes, so the payload is whatever
+ *  serialization the two sides agree on.
+ **/
+pub struct ShmMessage {
+    handler_id: i32,
+    payload: Vec<u8>,
+}
+
+impl ShmMessage {
+    pub fn new(handler_id: i32, payload: Vec<u8>) -> Self {
+        Self {
+            handler_id,
+            payload,
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn into_payload(self) -> Vec<u8> {
+        self.payload
+    }
+}
+
+impl Message for ShmMessage {
+    fn handler_id(&self) -> i32 {
+        self.handler_id
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+
+/**
+ *  Shared memory layout:
+ *      ShmHeader | slot 0 | slot 1 | ... | slot capacity-1
+ *  every slot is ShmSlotHeader followed by slot_size bytes of payload.
+ *
+ *  head and tail only ever grow, count = tail - head. A writer fills the slot first
+ *  and commits it with a single tail store (a reader likewise with head), so a peer
+ *  dying at any point leaves the ring consistent for the robust mutex to recover.
+ **/
+#[repr(C)]
+struct ShmHeader {
+    ready: AtomicU64,
+    mutex: libc::pthread_mutex_t,
+    //futex word, bumped whenever head or tail moves
+    seq: AtomicU32,
+    capacity: u32,
+    slot_size: u32,
+    head: u32,
+    tail: u32,
+}
+
+#[repr(C)]
+struct ShmSlotHeader {
+    handler_id: i32,
+    flags: u32,
+    len: u32,
+    _reserved: u32,
+}
+
+struct ShmLockGuard<'a> {
+    header: *mut ShmHeader,
+    _marker: PhantomData<&'a ShmMessageQueue>,
+}
+
+impl<'a> Drop for ShmLockGuard<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::pthread_mutex_unlock(ptr::addr_of_mut!((*self.header).mutex));
+        }
+    }
+}
+
+
+/**
+ *  ShmMessageQueue
+ *
+ *  MessageQueue living in a named POSIX shared memory region, usable from several
+ *  processes on the same machine. The ring is guarded by a process-shared robust
+ *  mutex, so a peer crashing while holding it does not lock the queue forever, and
+ *  blocked readers/writers sleep on a futex.
+ **/
+pub struct ShmMessageQueue {
+    fd: libc::c_int,
+    base: *mut u8,
+    len: usize,
+    //validated copies, a peer may scribble over the ones in the header at any time
+    capacity: u32,
+    slot_size: u32,
+    message_queue_handlers: MessageQueueHandlers,
+}
+
+//all access to the mapping goes through the process-shared mutex
+unsafe impl Send for ShmMessageQueue {}
+unsafe impl Sync for ShmMessageQueue {}
+
+impl ShmMessageQueue {
+    pub fn create(name: &str, capacity: u32, slot_size: u32) -> io::Result<Self> {
+        if capacity == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "capacity must not be 0"));
+        }
+
+        let c_name = shm_name(name)?;
+        let len = Self::region_len(capacity, slot_size);
+        unsafe {
+            let fd = libc::shm_open(c_name.as_ptr(), libc::O_CREAT | libc::O_EXCL | libc::O_RDWR, 0o600);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            if libc::ftruncate(fd, len as libc::off_t) != 0 {
+                let e = io::Error::last_os_error();
+                libc::close(fd);
+                libc::shm_unlink(c_name.as_ptr());
+                return Err(e);
+            }
+
+            let mut queue = match Self::map(fd, len) {
+                Ok(queue) => queue,
+                Err(e) => {
+                    libc::shm_unlink(c_name.as_ptr());
+                    return Err(e);
+                }
+            };
+
+            let header = queue.header();
+            let mut attr: libc::pthread_mutexattr_t = std::mem::zeroed();
+            libc::pthread_mutexattr_init(&mut attr);
+            libc::pthread_mutexattr_setpshared(&mut attr, libc::PTHREAD_PROCESS_SHARED);
+            libc::pthread_mutexattr_setrobust(&mut attr, libc::PTHREAD_MUTEX_ROBUST);
+            libc::pthread_mutex_init(ptr::addr_of_mut!((*header).mutex), &attr);
+            libc::pthread_mutexattr_destroy(&mut attr);
+
+            (*header).capacity = capacity;
+            (*header).slot_size = slot_size;
+            (*header).head = 0;
+            (*header).tail = 0;
+            (*header).ready.store(SHM_READY_MAGIC, Ordering::Release);
+            queue.capacity = capacity;
+            queue.slot_size = slot_size;
+            Ok(queue)
+        }
+    }
+
+    pub fn open(name: &str) -> io::Result<Self> {
+        let c_name = shm_name(name)?;
+        unsafe {
+            let fd = libc::shm_open(c_name.as_ptr(), libc::O_RDWR, 0);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            //the creator may still be sizing and initializing the region
+            let deadline = Instant::now() + Duration::from_secs(1);
+            loop {
+                let mut stat: libc::stat = std::mem::zeroed();
+                if libc::fstat(fd, &mut stat) != 0 {
+                    let e = io::Error::last_os_error();
+                    libc::close(fd);
+                    return Err(e);
+                }
+
+                if stat.st_size as usize >= size_of::<ShmHeader>() {
+                    let mut queue = Self::map(fd, stat.st_size as usize)?;
+                    while (*queue.header()).ready.load(Ordering::Acquire) != SHM_READY_MAGIC {
+                        if Instant::now() > deadline {
+                            return Err(io::Error::new(io::ErrorKind::TimedOut, "shared memory queue never became ready"));
+                        }
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    queue.check_header()?;
+                    return Ok(queue);
+                }
+
+                if Instant::now() > deadline {
+                    libc::close(fd);
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "shared memory queue never became ready"));
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    pub fn unlink(name: &str) -> io::Result<()> {
+        let c_name = shm_name(name)?;
+        if unsafe { libc::shm_unlink(c_name.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    //the header comes from another process, don't index slots with it unless they fit the mapping
+    unsafe fn check_header(&mut self) -> io::Result<()> {
+        let header = self.header();
+        let (capacity, slot_size) = ((*header).capacity, (*header).slot_size);
+        let region_len = (capacity as usize).checked_mul(Self::slot_stride(slot_size))
+            .and_then(|slots_len| slots_len.checked_add(round_up8(size_of::<ShmHeader>())));
+        match region_len {
+            Some(region_len) if capacity > 0 && region_len <= self.len => {
+                self.capacity = capacity;
+                self.slot_size = slot_size;
+                Ok(())
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("bad shared memory header capacity:{} slot_size:{} for {} bytes", capacity, slot_size, self.len))),
+        }
+    }
+
+    fn region_len(capacity: u32, slot_size: u32) -> usize {
+        round_up8(size_of::<ShmHeader>()) + capacity as usize * Self::slot_stride(slot_size)
+    }
+
+    fn slot_stride(slot_size: u32) -> usize {
+        size_of::<ShmSlotHeader>() + round_up8(slot_size as usize)
+    }
+
+    unsafe fn map(fd: libc::c_int, len: usize) -> io::Result<Self> {
+        let base = libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, 0);
+        if base == libc::MAP_FAILED {
+            let e = io::Error::last_os_error();
+            libc::close(fd);
+            return Err(e);
+        }
+
+        Ok(Self {
+            fd,
+            base: base as *mut u8,
+            len,
+            capacity: 0,
+            slot_size: 0,
+            message_queue_handlers: MessageQueueHandlers::new(),
+        })
+    }
+
+    fn header(&self) -> *mut ShmHeader {
+        self.base as *mut ShmHeader
+    }
+
+    unsafe fn slot(&self, index: u32) -> *mut ShmSlotHeader {
+        let offset = round_up8(size_of::<ShmHeader>())
+            + (index % self.capacity) as usize * Self::slot_stride(self.slot_size);
+        self.base.add(offset) as *mut ShmSlotHeader
+    }
+
+    fn lock(&self) -> ShmLockGuard<'_> {
+        let header = self.header();
+        unsafe {
+            let mutex = ptr::addr_of_mut!((*header).mutex);
+            let ret = libc::pthread_mutex_lock(mutex);
+            if ret == libc::EOWNERDEAD {
+                //the ring is committed by single stores, so whatever the dead peer
+                //left behind is already consistent
+                println!("ShmMessageQueue: previous lock owner died, recovering");
+                libc::pthread_mutex_consistent(mutex);
+            } else if ret != 0 {
+                panic!("ShmMessageQueue: pthread_mutex_lock failed {}", ret);
+            }
+        }
+
+        ShmLockGuard {
+            header,
+            _marker: PhantomData,
+        }
+    }
+
+    fn futex_wait(&self, seq: u32, timeout: Option<Duration>) {
+        let ts = timeout.map(|dur| libc::timespec {
+            tv_sec: dur.as_secs() as libc::time_t,
+            tv_nsec: dur.subsec_nanos() as libc::c_long,
+        });
+        let ts_ptr = ts.as_ref().map_or(ptr::null(), |ts| ts as *const libc::timespec);
+        unsafe {
+            libc::syscall(libc::SYS_futex, ptr::addr_of!((*self.header()).seq), libc::FUTEX_WAIT, seq, ts_ptr, ptr::null::<u32>(), 0);
+        }
+    }
+
+    fn futex_wake(&self) {
+        unsafe {
+            libc::syscall(libc::SYS_futex, ptr::addr_of!((*self.header()).seq), libc::FUTEX_WAKE, i32::MAX, ptr::null::<libc::timespec>(), ptr::null::<u32>(), 0);
+        }
+    }
+
+    fn remaining(deadline: Option<Instant>) -> Option<Option<Duration>> {
+        match deadline {
+            None => Some(None),
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    None
+                } else {
+                    Some(Some(deadline - now))
+                }
+            }
+        }
+    }
+
+    //WouldBlock when the ring is full
+    pub fn try_post_message(&self, message_option: Option<ShmMessage>) -> io::Result<()> {
+        self.post(message_option, false)
+    }
+
+    //waits while the ring is full
+    pub fn post_message(&self, message_option: Option<ShmMessage>) {
+        if let Err(e) = self.post(message_option, true) {
+            println!("ShmMessageQueue: failed to post message {}", e);
+        }
+    }
+
+    fn post(&self, message_option: Option<ShmMessage>, block: bool) -> io::Result<()> {
+        let header = self.header();
+        if let Some(msg) = message_option.as_ref() {
+            if msg.payload.len() > self.slot_size as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("payload {} bytes exceeds slot size {}", msg.payload.len(), self.slot_size)));
+            }
+        }
+
+        loop {
+            let guard = self.lock();
+            unsafe {
+                let (head, tail) = ((*header).head, (*header).tail);
+                if tail.wrapping_sub(head) < self.capacity {
+                    let slot = self.slot(tail);
+                    match message_option.as_ref() {
+                        Some(msg) => {
+                            (*slot).handler_id = msg.handler_id;
+                            (*slot).flags = 0;
+                            (*slot).len = msg.payload.len() as u32;
+                            ptr::copy_nonoverlapping(msg.payload.as_ptr(), slot.add(1) as *mut u8, msg.payload.len());
+                        }
+                        None => {
+                            (*slot).handler_id = -1;
+                            (*slot).flags = SHM_FLAG_STOP;
+                            (*slot).len = 0;
+                        }
+                    }
+                    (*header).tail = tail.wrapping_add(1);
+                    (*header).seq.fetch_add(1, Ordering::Release);
+                    drop(guard);
+                    self.futex_wake();
+                    return Ok(());
+                }
+
+                if !block {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "shared memory queue is full"));
+                }
+                let seq = (*header).seq.load(Ordering::Acquire);
+                drop(guard);
+                self.futex_wait(seq, None);
+            }
+        }
+    }
+
+    fn get_message_until(&self, deadline: Option<Instant>) -> Option<ShmMessage> {
+        let header = self.header();
+        loop {
+            let guard = self.lock();
+            unsafe {
+                let (head, tail) = ((*header).head, (*header).tail);
+                if head != tail {
+                    let slot = self.slot(head);
+                    let message_option = if (*slot).flags & SHM_FLAG_STOP != 0 {
+                        None
+                    } else {
+                        let len = ((*slot).len).min(self.slot_size) as usize;
+                        let mut payload = vec![0u8; len];
+                        ptr::copy_nonoverlapping(slot.add(1) as *const u8, payload.as_mut_ptr(), len);
+                        Some(ShmMessage::new((*slot).handler_id, payload))
+                    };
+                    (*header).head = head.wrapping_add(1);
+                    (*header).seq.fetch_add(1, Ordering::Release);
+                    drop(guard);
+                    self.futex_wake();
+                    return message_option;
+                }
+
+                let seq = (*header).seq.load(Ordering::Acquire);
+                drop(guard);
+                match Self::remaining(deadline) {
+                    Some(timeout) => self.futex_wait(seq, timeout),
+                    None => return None,
+                }
+            }
+        }
+    }
+
+    pub fn get_message(&self) -> Option<ShmMessage> {
+        self.get_message_until(None)
+    }
+
+    pub fn get_message_timeout(&self, duration: Duration) -> Option<ShmMessage> {
+        self.get_message_until(Instant::now().checked_add(duration))
+    }
+
+    pub fn register_message_handler(&self, handler_id: i32, handler: Arc<dyn MessageHandler + Send + Sync>) {
+        self.message_queue_handlers.register_message_handler(handler_id, handler);
+    }
+
+    pub fn process_next_message(&self) -> bool {
+        match self.get_message() {
+            Some(msg) => self.message_queue_handlers.dispatch_message(Some(Box::new(msg))),
+            None => false,
+        }
+    }
+}
+
+impl Drop for ShmMessageQueue {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    fn unique_name() -> String {
+        format!("msgq_test_{}_{}", std::process::id(), NEXT_ID.fetch_add(1, Ordering::SeqCst))
+    }
+
+    #[test]
+    fn post_and_get_between_mappings() {
+        let name = unique_name();
+        let writer = ShmMessageQueue::create(&name, 4, 64).unwrap();
+        let reader = ShmMessageQueue::open(&name).unwrap();
+        ShmMessageQueue::unlink(&name).unwrap();
+
+        let producer = thread::spawn(move || {
+            for i in 0..20u8 {
+                writer.post_message(Some(ShmMessage::new(i as i32, vec![i; i as usize])));
+            }
+            writer.post_message(None);
+        });
+
+        for i in 0..20u8 {
+            let msg = reader.get_message().unwrap();
+            assert_eq!(msg.handler_id(), i as i32);
+            assert_eq!(msg.payload(), &vec![i; i as usize][..]);
+        }
+        assert!(reader.get_message().is_none());
+        assert!(reader.get_message_timeout(Duration::from_millis(10)).is_none());
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn oversized_payload_is_rejected() {
+        let name = unique_name();
+        let queue = ShmMessageQueue::create(&name, 1, 8).unwrap();
+        ShmMessageQueue::unlink(&name).unwrap();
+        assert!(queue.try_post_message(Some(ShmMessage::new(1, vec![0; 9]))).is_err());
+    }
+
+    #[test]
+    fn full_ring_would_block() {
+        let name = unique_name();
+        let queue = ShmMessageQueue::create(&name, 1, 8).unwrap();
+        ShmMessageQueue::unlink(&name).unwrap();
+        queue.try_post_message(Some(ShmMessage::new(1, vec![1]))).unwrap();
+        let e = queue.try_post_message(Some(ShmMessage::new(2, vec![2]))).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(queue.get_message().unwrap().handler_id(), 1);
+        queue.try_post_message(Some(ShmMessage::new(2, vec![2]))).unwrap();
+    }
+
+    #[test]
+    fn corrupted_header_is_rejected() {
+        let name = unique_name();
+        let writer = ShmMessageQueue::create(&name, 2, 8).unwrap();
+        unsafe {
+            (*writer.header()).capacity = 0;
+        }
+        assert_eq!(ShmMessageQueue::open(&name).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        unsafe {
+            (*writer.header()).capacity = 3;
+        }
+        assert_eq!(ShmMessageQueue::open(&name).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        unsafe {
+            (*writer.header()).capacity = 2;
+        }
+        assert!(ShmMessageQueue::open(&name).is_ok());
+        ShmMessageQueue::unlink(&name).unwrap();
+    }
+
+    #[test]
+    fn header_changed_after_open_is_not_trusted() {
+        let name = unique_name();
+        let writer = ShmMessageQueue::create(&name, 2, 8).unwrap();
+        let reader = ShmMessageQueue::open(&name).unwrap();
+        ShmMessageQueue::unlink(&name).unwrap();
+        for (capacity, slot_size) in [(0, 8), (1000, 1 << 20)] {
+            unsafe {
+                (*writer.header()).capacity = capacity;
+                (*writer.header()).slot_size = slot_size;
+            }
+            assert!(reader.try_post_message(Some(ShmMessage::new(1, vec![0; 9]))).is_err());
+            reader.try_post_message(Some(ShmMessage::new(1, vec![1; 8]))).unwrap();
+            reader.try_post_message(Some(ShmMessage::new(2, vec![2; 8]))).unwrap();
+            assert_eq!(reader.try_post_message(Some(ShmMessage::new(3, vec![3]))).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+            //a peer lying about a slot's length gets no more than slot_size bytes
+            unsafe {
+                (*reader.slot((*reader.header()).head)).len = 1 << 20;
+            }
+            assert_eq!(reader.get_message().unwrap().payload(), &[1; 8]);
+            assert_eq!(writer.get_message().unwrap().payload(), &[2; 8]);
+        }
+    }
+
+    #[test]
+    fn dead_lock_owner_is_recovered() {
+        let name = unique_name();
+        let queue = ShmMessageQueue::create(&name, 2, 8).unwrap();
+        ShmMessageQueue::unlink(&name).unwrap();
+
+        unsafe {
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                //child: take the lock and die without releasing it
+                std::mem::forget(queue.lock());
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            libc::waitpid(pid, &mut status, 0);
+        }
+
+        queue.post_message(Some(ShmMessage::new(3, vec![1, 2, 3])));
+        assert_eq!(queue.get_message().unwrap().payload(), &[1, 2, 3]);
+    }
+}
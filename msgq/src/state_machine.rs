@@ -0,0 +1,511 @@
+use std::sync::{Mutex, Arc, OnceLock};
+use std::collections::VecDeque;
+use std::any::Any;
+use std::time::SystemTime;
+use crate::message_queue::*;
+use crate::handler::Handler;
+
+
+pub type StateId = usize;
+
+/**
+ *  State
+ *
+ *  process_message() returns true when the message is handled, otherwise the
+ *  parent state gets it, and so on up to the root.
+ *
+ *  enter(), exit() and process_message() run with the StateMachine locked, so they
+ *  must not call back into it, e.g. current_state_name() or transition_log() would
+ *  deadlock. Use the StateContext instead.
+ **/
+pub trait State {
+    fn name(&self) -> &str;
+
+    fn enter(&mut self, _ctx: &mut StateContext) {
+    }
+
+    fn exit(&mut self, _ctx: &mut StateContext) {
+    }
+
+    fn process_message(&mut self, ctx: &mut StateContext, msg: &(dyn Message + Send)) -> bool;
+}
+
+
+/**
+ *  StateContext
+ *  what a State may ask of its StateMachine while it runs
+ **/
+pub struct StateContext {
+    handler: Option<Handler>,
+    destination: Option<StateId>,
+    defer: bool,
+    quit: bool,
+}
+
+impl StateContext {
+    fn new(handler: Option<Handler>) -> Self {
+        Self {
+            handler,
+            destination: None,
+            defer: false,
+            quit: false,
+        }
+    }
+
+    //done after the current message, exits up to the common ancestor and enters down to destination.
+    //A StateId not returned by this StateMachine's add_state() is ignored
+    pub fn transition_to(&mut self, destination: StateId) {
+        self.destination = Some(destination);
+    }
+
+    //keep the current message and process it again after the next transition
+    pub fn defer_message(&mut self) {
+        self.defer = true;
+    }
+
+    //exit every active state, later messages are dropped
+    pub fn quit(&mut self) {
+        self.quit = true;
+    }
+
+    //posts to this state machine's handler_id
+    pub fn handler(&self) -> Option<&Handler> {
+        self.handler.as_ref()
+    }
+}
+
+
+/**
+ *  TransitionLogRecord
+ **/
+#[derive(Clone, Debug)]
+pub struct TransitionLogRecord {
+    pub time: SystemTime,
+    pub handler_id: i32,
+    //deepest active state when the message arrived
+    pub original_state: Option<String>,
+    //state whose process_message() returned true, None if nobody handled it
+    pub handled_by: Option<String>,
+    pub destination: Option<String>,
+    pub deferred: bool,
+}
+
+
+struct StateInfo {
+    state: Box<dyn State + Send>,
+    parent: Option<StateId>,
+}
+
+struct StateMachineInner {
+    states: Vec<StateInfo>,
+    initial_state: Option<StateId>,
+    //active states from the root down to the current leaf
+    active: Vec<StateId>,
+    deferred: VecDeque<Box<dyn Message + Send>>,
+    log: VecDeque<TransitionLogRecord>,
+    log_size: usize,
+    started: bool,
+    quitted: bool,
+}
+
+impl StateMachineInner {
+    fn path_to_root(&self, state_id: StateId) -> Vec<StateId> {
+        let mut path = vec![state_id];
+        let mut parent = self.states[state_id].parent;
+        while let Some(parent_id) = parent {
+            path.push(parent_id);
+            parent = self.states[parent_id].parent;
+        }
+        path.reverse();
+        path
+    }
+
+    fn state_name(&self, state_id: StateId) -> String {
+        self.states[state_id].state.name().to_string()
+    }
+
+    fn enter_path(&mut self, path: &[StateId], ctx: &mut StateContext) {
+        for state_id in path {
+            self.states[*state_id].state.enter(ctx);
+            self.active.push(*state_id);
+        }
+    }
+
+    fn exit_to_depth(&mut self, depth: usize, ctx: &mut StateContext) {
+        while self.active.len() > depth {
+            let state_id = self.active.pop().unwrap();
+            self.states[state_id].state.exit(ctx);
+        }
+    }
+
+    fn transition_to(&mut self, destination: StateId, handler: &Option<Handler>) {
+        let path = self.path_to_root(destination);
+        let common = self.active.iter().zip(path.iter()).take_while(|(a, b)| a == b).count();
+        let mut ctx = StateContext::new(handler.clone());
+        self.exit_to_depth(common, &mut ctx);
+        self.enter_path(&path[common..], &mut ctx);
+    }
+
+    fn add_log(&mut self, record: TransitionLogRecord) {
+        if self.log_size == 0 {
+            return;
+        }
+        while self.log.len() >= self.log_size {
+            self.log.pop_front();
+        }
+        self.log.push_back(record);
+    }
+
+    //returns the message back when it was deferred
+    fn process_message(&mut self, box_msg: Box<dyn Message + Send>, handler: &Option<Handler>) -> Option<Box<dyn Message + Send>> {
+        let mut ctx = StateContext::new(handler.clone());
+        let mut handled_by = None;
+        for depth in (0..self.active.len()).rev() {
+            let state_id = self.active[depth];
+            if self.states[state_id].state.process_message(&mut ctx, box_msg.as_ref()) {
+                handled_by = Some(state_id);
+                break;
+            }
+        }
+
+        if handled_by.is_none() {
+            println!("StateMachine: unhandled message handler_id:{} in {:?}",
+                box_msg.handler_id(), self.active.last().map(|state_id| self.state_name(*state_id)));
+        }
+
+        if let Some(destination) = ctx.destination.filter(|state_id| *state_id >= self.states.len()) {
+            println!("StateMachine: ignoring transition to unknown state {} from {:?}",
+                destination, self.active.last().map(|state_id| self.state_name(*state_id)));
+            ctx.destination = None;
+        }
+
+        let record = TransitionLogRecord {
+            time: SystemTime::now(),
+            handler_id: box_msg.handler_id(),
+            original_state: self.active.last().map(|state_id| self.state_name(*state_id)),
+            handled_by: handled_by.map(|state_id| self.state_name(state_id)),
+            destination: ctx.destination.map(|state_id| self.state_name(state_id)),
+            deferred: ctx.defer,
+        };
+        self.add_log(record);
+
+        let deferred_msg = if ctx.defer { Some(box_msg) } else { None };
+        if ctx.quit {
+            self.exit_to_depth(0, &mut ctx);
+            self.quitted = true;
+            self.deferred.clear();
+            return None;
+        }
+
+        if let Some(destination) = ctx.destination {
+            self.transition_to(destination, handler);
+            //deferred messages go first after a transition, in their original order
+            let mut pending = std::mem::take(&mut self.deferred);
+            while let Some(pending_msg) = pending.pop_front() {
+                if let Some(still_deferred) = self.process_message(pending_msg, handler) {
+                    self.deferred.push_back(still_deferred);
+                }
+                if self.quitted {
+                    return None;
+                }
+            }
+        }
+        deferred_msg
+    }
+}
+
+
+/**
+ *  StateMachineInit
+ *  posted by start() so the initial states are entered on the queue's thread
+ **/
+struct StateMachineInit {
+    handler_id: i32,
+}
+
+impl Message for StateMachineInit {
+    fn handler_id(&self) -> i32 {
+        self.handler_id
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+
+/**
+ *  StateMachine
+ *
+ *  Hierarchical state machine running as a MessageHandler on a MessageQueue, in the
+ *  spirit of Android's StateMachine: a message goes to the current state first and
+ *  bubbles up to its parents until one of them handles it.
+ **/
+pub struct StateMachine {
+    name: String,
+    inner: Mutex<StateMachineInner>,
+    handler: OnceLock<Handler>,
+}
+
+impl StateMachine {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            inner: Mutex::new(StateMachineInner {
+                states: Vec::new(),
+                initial_state: None,
+                active: Vec::new(),
+                deferred: VecDeque::new(),
+                log: VecDeque::new(),
+                log_size: 20,
+                started: false,
+                quitted: false,
+            }),
+            handler: OnceLock::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn add_state(&self, state: Box<dyn State + Send>, parent: Option<StateId>) -> StateId {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(parent_id) = parent {
+            assert!(parent_id < inner.states.len(), "unknown parent state {}", parent_id);
+        }
+        inner.states.push(StateInfo { state, parent });
+        inner.states.len() - 1
+    }
+
+    pub fn set_initial_state(&self, state_id: StateId) {
+        let mut inner = self.inner.lock().unwrap();
+        assert!(state_id < inner.states.len(), "unknown initial state {}", state_id);
+        inner.initial_state = Some(state_id);
+    }
+
+    //number of records kept by transition_log(), 0 disables logging
+    pub fn set_log_size(&self, log_size: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.log_size = log_size;
+        while inner.log.len() > log_size {
+            inner.log.pop_front();
+        }
+    }
+
+    //not from inside a State, see State
+    pub fn transition_log(&self) -> Vec<TransitionLogRecord> {
+        self.inner.lock().unwrap().log.iter().cloned().collect()
+    }
+
+    pub fn dump(&self) -> String {
+        let mut s = format!("StateMachine {} current:{:?}\n", self.name, self.current_state_name());
+        for (i, record) in self.transition_log().iter().enumerate() {
+            s.push_str(&format!("  {}: handler_id:{} org:{:?} handled_by:{:?} dest:{:?}{}\n", i, record.handler_id,
+                record.original_state, record.handled_by, record.destination, if record.deferred { " deferred" } else { "" }));
+        }
+        s
+    }
+
+    //not from inside a State, see State
+    pub fn current_state_name(&self) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner.active.last().map(|state_id| inner.state_name(*state_id))
+    }
+
+    pub fn handler(&self) -> Option<&Handler> {
+        self.handler.get()
+    }
+
+    /**
+     *  Register as handler_id on message_queue and enter the initial state there.
+     **/
+    pub fn start(self: &Arc<Self>, message_queue: Arc<MessageQueue>, handler_id: i32) {
+        if self.handler.set(Handler::new(message_queue.clone(), handler_id)).is_err() {
+            println!("StateMachine {} already started", self.name);
+            return;
+        }
+        message_queue.register_message_handler(handler_id, self.clone());
+        message_queue.post_message(Some(Box::new(StateMachineInit { handler_id })));
+    }
+
+    pub fn send_message(&self, box_msg: Box<dyn Message + Send>) -> bool {
+        match self.handler.get() {
+            Some(handler) => handler.post_message(box_msg),
+            None => false,
+        }
+    }
+}
+
+impl MessageHandler for StateMachine {
+    fn on_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+        let box_msg = match option_box_msg {
+            Some(box_msg) => box_msg,
+            None => return false,
+        };
+
+        let handler = self.handler.get().cloned();
+        let mut inner = self.inner.lock().unwrap();
+        if box_msg.as_any().is::<StateMachineInit>() {
+            if !inner.started {
+                inner.started = true;
+                if let Some(initial_state) = inner.initial_state {
+                    inner.transition_to(initial_state, &handler);
+                }
+            }
+            return true;
+        }
+
+        if inner.quitted {
+            return true;
+        }
+
+        if let Some(deferred_msg) = inner.process_message(box_msg, &handler) {
+            inner.deferred.push_back(deferred_msg);
+        }
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HANDLER_ID: i32 = 7;
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    enum Cmd {
+        Go,
+        Wait,
+        Back,
+        Stop,
+    }
+
+    struct CmdMessage {
+        cmd: Cmd,
+    }
+
+    impl Message for CmdMessage {
+        fn handler_id(&self) -> i32 {
+            HANDLER_ID
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn cmd_of(msg: &(dyn Message + Send)) -> Cmd {
+        msg.as_any().downcast_ref::<CmdMessage>().unwrap().cmd
+    }
+
+    type OnMessage = Box<dyn FnMut(&mut StateContext, Cmd) -> bool + Send>;
+
+    //records enter/exit/handled events into a shared trace
+    struct TraceState {
+        name: String,
+        trace: Arc<Mutex<Vec<String>>>,
+        on_message: OnMessage,
+    }
+
+    impl State for TraceState {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn enter(&mut self, _ctx: &mut StateContext) {
+            self.trace.lock().unwrap().push(format!("enter {}", self.name));
+        }
+
+        fn exit(&mut self, _ctx: &mut StateContext) {
+            self.trace.lock().unwrap().push(format!("exit {}", self.name));
+        }
+
+        fn process_message(&mut self, ctx: &mut StateContext, msg: &(dyn Message + Send)) -> bool {
+            let cmd = cmd_of(msg);
+            let handled = (self.on_message)(ctx, cmd);
+            if handled {
+                self.trace.lock().unwrap().push(format!("{} {:?}", self.name, cmd));
+            }
+            handled
+        }
+    }
+
+    #[test]
+    fn parent_handles_and_deferred_messages_replay() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let sm = Arc::new(StateMachine::new("test"));
+        let state = |name: &str, on_message: OnMessage| {
+            Box::new(TraceState { name: name.to_string(), trace: trace.clone(), on_message })
+        };
+
+        // parent
+        //  |- idle
+        //  |- busy
+        let parent = sm.add_state(state("parent", Box::new(|ctx, cmd| match cmd {
+            Cmd::Back => { ctx.transition_to(1); true }
+            Cmd::Stop => { ctx.quit(); true }
+            _ => false,
+        })), None);
+        let idle = sm.add_state(state("idle", Box::new(|ctx, cmd| match cmd {
+            Cmd::Go => { ctx.transition_to(2); true }
+            Cmd::Wait => true,
+            _ => false,
+        })), Some(parent));
+        let busy = sm.add_state(state("busy", Box::new(|ctx, cmd| match cmd {
+            Cmd::Wait => { ctx.defer_message(); true }
+            _ => false,
+        })), Some(parent));
+        assert_eq!((idle, busy), (1, 2));
+        sm.set_initial_state(idle);
+
+        let message_queue = Arc::new(MessageQueue::new());
+        sm.start(message_queue.clone(), HANDLER_ID);
+        for cmd in [Cmd::Go, Cmd::Wait, Cmd::Back, Cmd::Stop, Cmd::Go].iter() {
+            sm.send_message(Box::new(CmdMessage { cmd: *cmd }));
+        }
+        for _ in 0..6 {
+            assert!(message_queue.process_next_message());
+        }
+
+        assert_eq!(*trace.lock().unwrap(), vec![
+            "enter parent", "enter idle",
+            "idle Go", "exit idle", "enter busy",
+            "busy Wait",
+            "parent Back", "exit busy", "enter idle",
+            "idle Wait",
+            "parent Stop", "exit idle", "exit parent",
+        ]);
+        assert_eq!(sm.current_state_name(), None);
+
+        let log = sm.transition_log();
+        assert_eq!(log.len(), 5);
+        assert_eq!(log[1].original_state.as_deref(), Some("busy"));
+        assert!(log[1].deferred);
+        assert_eq!(log[2].handled_by.as_deref(), Some("parent"));
+        assert_eq!(log[2].destination.as_deref(), Some("idle"));
+        assert_eq!(log[3].handled_by.as_deref(), Some("idle"));
+    }
+
+    #[test]
+    fn unknown_destination_is_ignored() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let sm = Arc::new(StateMachine::new("test"));
+        let idle = sm.add_state(Box::new(TraceState { name: "idle".to_string(), trace: trace.clone(), on_message: Box::new(|ctx, cmd| {
+            ctx.transition_to(99);
+            cmd == Cmd::Go
+        })}), None);
+        sm.set_initial_state(idle);
+
+        let message_queue = Arc::new(MessageQueue::new());
+        sm.start(message_queue.clone(), HANDLER_ID);
+        sm.send_message(Box::new(CmdMessage { cmd: Cmd::Go }));
+        for _ in 0..2 {
+            assert!(message_queue.process_next_message());
+        }
+        assert_eq!(sm.current_state_name().as_deref(), Some("idle"));
+        assert_eq!(sm.transition_log()[0].destination, None);
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| sm.set_initial_state(99))).is_err());
+    }
+}
@@ -0,0 +1,17 @@
+//Locks, atomics and threads of MessageQueueVector, MessageThread and
+//MessageQueueHandlers. A normal build uses std, RUSTFLAGS="--cfg loom" swaps in
+//loom's copies so loom_tests can run every interleaving of them.
+
+#[cfg(not(loom))]
+pub(crate) use std::sync::{Condvar, Mutex, RwLock};
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize};
+#[cfg(not(loom))]
+pub(crate) use std::thread;
+
+#[cfg(loom)]
+pub(crate) use loom::sync::{Condvar, Mutex, RwLock};
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize};
+#[cfg(loom)]
+pub(crate) use loom::thread;
@@ -0,0 +1,168 @@
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use crate::generic::{MessageQueue, MessageMeta};
+use crate::introspection::WorkerStatus;
+
+
+/**
+ *  WorkStealingGroup
+ *
+ *  Opt-in group of MessageThreads, each still owning its MessageQueue. A member
+ *  thread with nothing of its own to do looks at the sibling queue with the most
+ *  pending messages every idle_interval and takes up to half of it, at most
+ *  steal_batch. Stolen messages run on the thief's thread but through the handler
+ *  of the queue they were posted to. Thread-affine messages are never stolen, and
+ *  neither are messages of handlers with a HandlerLimit.
+ **/
+pub struct WorkStealingGroup<M: Send + 'static> {
+    members: RwLock<Vec<Arc<MessageQueue<M>>>>,
+    steal_batch: usize,
+    idle_interval: Duration,
+    stolen_count: AtomicUsize,
+}
+
+impl<M: Send + 'static> WorkStealingGroup<M> {
+    pub fn new(steal_batch: usize, idle_interval: Duration) -> Self {
+        Self {
+            members: RwLock::new(Vec::new()),
+            steal_batch: steal_batch.max(1),
+            idle_interval,
+            stolen_count: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn idle_interval(&self) -> Duration {
+        self.idle_interval
+    }
+
+    //messages run by a thread other than their queue's so far
+    pub fn stolen_count(&self) -> usize {
+        self.stolen_count.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_member(&self, message_queue: Arc<MessageQueue<M>>) {
+        let mut members = self.members.write().unwrap();
+        if !members.iter().any(|member| member.same_queue(&message_queue)) {
+            members.push(message_queue);
+        }
+    }
+
+    pub(crate) fn remove_member(&self, message_queue: &MessageQueue<M>) {
+        self.members.write().unwrap().retain(|member| !member.same_queue(message_queue));
+    }
+
+    /**
+     *  Steal for thief and run what was stolen, returns how many messages ran.
+     **/
+    pub fn steal_and_run(&self, thief: &MessageQueue<M>) -> usize {
+        self.steal_and_run_for(thief, None)
+    }
+
+    pub(crate) fn steal_and_run_for(&self, thief: &MessageQueue<M>, worker_status: Option<&WorkerStatus>) -> usize {
+        let victim = {
+            let members = self.members.read().unwrap();
+            members.iter()
+                .filter(|member| !member.same_queue(thief))
+                .map(|member| (member.pending_count(), member))
+                .filter(|(pending_count, _)| *pending_count > 0)
+                .max_by_key(|(pending_count, _)| *pending_count)
+                .map(|(pending_count, member)| (pending_count, member.clone()))
+        };
+        let (pending_count, victim) = match victim {
+            Some(victim) => victim,
+            None => return 0,
+        };
+
+        let stolen: Vec<(M, MessageMeta)> = victim.steal_messages(pending_count.div_ceil(2).min(self.steal_batch));
+        let count = stolen.len();
+        self.stolen_count.fetch_add(count, Ordering::Relaxed);
+        for (message, meta) in stolen {
+            if let Some(worker_status) = worker_status {
+                worker_status.begin(meta.handler_id);
+            }
+            victim.dispatch_message_with_meta(Some(message), meta);
+            if let Some(worker_status) = worker_status {
+                worker_status.end(1);
+            }
+        }
+        count
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crate::message_queue::*;
+
+    struct WorkMessage {
+        id: usize,
+        thread_affine: bool,
+    }
+
+    impl Message for WorkMessage {
+        fn handler_id(&self) -> i32 {
+            1
+        }
+
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+
+        fn is_thread_affine(&self) -> bool {
+            self.thread_affine
+        }
+    }
+
+    struct SlowHandler {
+        //(id, thread_affine, thread name)
+        runs: Mutex<Vec<(usize, bool, String)>>,
+    }
+
+    impl MessageHandler for SlowHandler {
+        fn on_message(&self, option_box_msg: Option<Box<dyn Message + Send>>) -> bool {
+            let box_msg = option_box_msg.unwrap();
+            let work_msg = box_msg.as_any().downcast_ref::<WorkMessage>().unwrap();
+            thread::sleep(Duration::from_millis(5));
+            let thread_name = thread::current().name().unwrap_or("").to_string();
+            self.runs.lock().unwrap().push((work_msg.id, work_msg.thread_affine, thread_name));
+            true
+        }
+    }
+
+    #[test]
+    fn idle_thread_steals_all_but_thread_affine_messages() {
+        let group = Arc::new(WorkStealingGroup::new(4, Duration::from_millis(1)));
+        let hot_queue = Arc::new(MessageQueue::with_options(Some("hot"), None));
+        let idle_queue = Arc::new(MessageQueue::with_options(Some("idle"), None));
+        let handler = Arc::new(SlowHandler { runs: Mutex::new(Vec::new()) });
+        hot_queue.register_message_handler(1, handler.clone());
+
+        let mut hot_thread = MessageThread::new(hot_queue.clone());
+        let mut idle_thread = MessageThread::new(idle_queue.clone());
+        hot_thread.join_work_stealing_group(group.clone());
+        idle_thread.join_work_stealing_group(group.clone());
+        for id in 0..40 {
+            hot_queue.post_message(Some(Box::new(WorkMessage { id, thread_affine: id % 4 == 0 })));
+        }
+        hot_thread.start();
+        idle_thread.start();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handler.runs.lock().unwrap().len() < 40 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        hot_thread.stop();
+        idle_thread.stop();
+
+        let runs = handler.runs.lock().unwrap();
+        assert_eq!(runs.len(), 40);
+        assert!(runs.iter().filter(|(_, thread_affine, _)| *thread_affine).all(|(_, _, thread_name)| thread_name == "hot"));
+        let stolen = runs.iter().filter(|(_, _, thread_name)| thread_name == "idle").count();
+        assert!(stolen > 0);
+        assert_eq!(stolen, group.stolen_count());
+    }
+}
@@ -60,11 +60,11 @@ pub fn test() {
     //};
     let e = open_test(file_name);
     if e.is_err() {
-        log!("failed to open {}\n", format!("{:?}", e.err()));
+        log!("failed to open {:?}\n", e.err());
     }
 
     if let Err(e) = open_test(file_name) {
-        log!("failed to open {}\n", format!("{:?}", e));
+        log!("failed to open {:?}\n", e);
     }
 
     match create_and_write_1(file_name) {